@@ -0,0 +1,55 @@
+use crate::config::{ApplistType, Config};
+use anyhow::Context;
+use log::warn;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Which app UIDs have their outgoing traffic queued to the engine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AppFilter {
+    All,
+    Only(Vec<u32>),
+    Except(Vec<u32>),
+}
+
+/// Parses `packages.list`, where every line starts with `<package> <uid> ...`.
+pub fn read_packages(packages_list: &Path) -> anyhow::Result<HashMap<String, u32>> {
+    let content = fs::read_to_string(packages_list)
+        .with_context(|| format!("Failed to read {}", packages_list.display()))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let package = fields.next()?;
+            let uid = fields.next()?.parse().ok()?;
+            Some((package.to_string(), uid))
+        })
+        .collect())
+}
+
+fn resolve_uids(packages: &[String], known: &HashMap<String, u32>) -> Vec<u32> {
+    let mut uids = Vec::new();
+    for package in packages {
+        match known.get(package) {
+            Some(uid) if !uids.contains(uid) => uids.push(*uid),
+            Some(_) => {}
+            None => warn!("Package {package} is not installed, skipping"),
+        }
+    }
+    uids
+}
+
+pub fn app_filter(config: &Config, packages_list: &Path) -> anyhow::Result<AppFilter> {
+    Ok(match config.app_list() {
+        ApplistType::None => AppFilter::All,
+        ApplistType::Whitelist => AppFilter::Only(resolve_uids(
+            config.whitelist(),
+            &read_packages(packages_list)?,
+        )),
+        ApplistType::Blacklist => AppFilter::Except(resolve_uids(
+            config.blacklist(),
+            &read_packages(packages_list)?,
+        )),
+    })
+}
@@ -1,4 +1,6 @@
 pub mod commands;
+pub mod manifest;
+pub mod strategy;
 
 use clap::Parser;
 use commands::Command;
@@ -1,6 +1,11 @@
 use crate::autostart::{get_autostart, set_autostart};
-use crate::service::{restart_service, service_status, start_service, stop_service};
+use crate::service::{
+    read_config, reload_service, restart_service, service_status, start_service, status_report,
+    stop_service, supervise_service,
+};
 use crate::{nfqws_version, nfqws2_version, run_nfqws, run_nfqws2};
+use crate::cli::manifest::ManifestCommand;
+use crate::cli::strategy::StrategyCommand;
 use clap::Subcommand;
 
 #[derive(Subcommand)]
@@ -14,8 +19,34 @@ pub enum Command {
     /// Restart the service
     Restart,
 
+    /// Reload config and strategy without touching firewall rules
+    Reload,
+
     /// Show the current service status
-    Status,
+    Status {
+        /// Print a detailed report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Run the engine in the foreground, restarting it when it crashes
+    Supervise {
+        /// Instance to run, required when several are configured
+        #[arg(long)]
+        instance: Option<String>,
+    },
+
+    /// Inspect strategies
+    Strategy {
+        #[command(subcommand)]
+        command: StrategyCommand,
+    },
+
+    /// Maintain manifests
+    Manifest {
+        #[command(subcommand)]
+        command: ManifestCommand,
+    },
 
     /// Enable or disable automatic restart
     SetAutostart,
@@ -47,7 +78,13 @@ impl Command {
             Command::Start => start_service().await?,
             Command::Stop => stop_service().await?,
             Command::Restart => restart_service().await?,
-            Command::Status => {
+            Command::Reload => reload_service().await?,
+            Command::Supervise { instance } => supervise_service(instance.as_deref()).await?,
+            Command::Status { json: true } => {
+                println!("{}", serde_json::to_string_pretty(&status_report().await?)?)
+            }
+            // Only the pid files are read here; the details cost firewall and process queries.
+            Command::Status { json: false } => {
                 println!(
                     "zaprett is {}",
                     if service_status().await? {
@@ -57,12 +94,18 @@ impl Command {
                     }
                 );
             }
+            Command::Strategy { command } => command.exec().await?,
+            Command::Manifest { command } => command.exec().await?,
             Command::SetAutostart => set_autostart().await?,
             Command::GetAutostart => println!("{}", get_autostart()),
             Command::NfqwsVersion => println!("{}", nfqws_version()),
             Command::Nfqws2Version => println!("{}", nfqws2_version()),
-            Command::RunNfqws { args } => run_nfqws(&args.join(" "))?,
-            Command::RunNfqws2 { args } => run_nfqws2(&args.join(" "))?,
+            Command::RunNfqws { args } => {
+                run_nfqws(args, read_config().await?.queue().num())?
+            }
+            Command::RunNfqws2 { args } => {
+                run_nfqws2(args, read_config().await?.queue().num())?
+            }
         }
 
         Ok(())
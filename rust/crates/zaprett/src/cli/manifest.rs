@@ -0,0 +1,60 @@
+use crate::{read_manifest, sha256};
+use anyhow::{Context, bail};
+use clap::Subcommand;
+use serde::Serialize;
+use serde_json::Value;
+use serde_json::ser::{PrettyFormatter, Serializer};
+use std::path::PathBuf;
+use tokio::fs;
+
+#[derive(Subcommand)]
+pub enum ManifestCommand {
+    /// Record the sha256 and size of the manifest's file in the manifest
+    Hash {
+        /// Path to the manifest
+        path: PathBuf,
+    },
+}
+
+impl ManifestCommand {
+    pub async fn exec(&self) -> anyhow::Result<()> {
+        match self {
+            ManifestCommand::Hash { path } => {
+                let manifest = read_manifest(path)?;
+                let content = fs::read(manifest.file())
+                    .await
+                    .with_context(|| format!("Failed to read {}", manifest.file()))?;
+                let hash = sha256(&content);
+                println!("{hash}  {}", manifest.file());
+
+                // Edit the raw JSON so relative paths stay relative
+                // Keys keep their order and the file its indentation.
+                let original = fs::read_to_string(path).await?;
+                let mut raw: Value = serde_json::from_str(&original)?;
+                let Some(fields) = raw.as_object_mut() else {
+                    bail!("Manifest is not a JSON object: {}", path.display());
+                };
+                fields.insert("sha256".to_string(), hash.into());
+                fields.insert("size".to_string(), content.len().into());
+                let mut json = Vec::new();
+                let formatter = PrettyFormatter::with_indent(indent(&original).as_bytes());
+                raw.serialize(&mut Serializer::with_formatter(&mut json, formatter))?;
+                json.push(b'\n');
+                fs::write(path, json)
+                    .await
+                    .with_context(|| format!("Failed to write manifest: {}", path.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The indentation of the first indented line of `json`.
+fn indent(json: &str) -> &str {
+    json.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .find(|indent| !indent.is_empty())
+        .unwrap_or("    ")
+}
@@ -0,0 +1,46 @@
+use crate::config::ServiceType;
+use crate::service::render_argv;
+use clap::Subcommand;
+
+#[derive(Subcommand)]
+pub enum StrategyCommand {
+    /// Substitute the strategy variables and print the resulting engine argv
+    Render {
+        /// Instance to render, defaults to the first configured one
+        #[arg(long)]
+        instance: Option<String>,
+
+        /// Engine to render for instead of the instance's own
+        #[arg(long, value_enum)]
+        engine: Option<ServiceType>,
+
+        /// Strategy manifest to render instead of the configured one
+        #[arg(long)]
+        strategy: Option<String>,
+
+        /// Print the argv as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+impl StrategyCommand {
+    pub async fn exec(&self) -> anyhow::Result<()> {
+        match self {
+            StrategyCommand::Render { instance, engine, strategy, json } => {
+                let (argv, warnings) =
+                    render_argv(instance.as_deref(), *engine, strategy.as_deref()).await?;
+                for warning in warnings {
+                    eprintln!("warning: {warning}");
+                }
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&argv)?);
+                } else {
+                    argv.iter().for_each(|arg| println!("{arg}"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,8 +1,13 @@
-use std::path::{Path, PathBuf};
-use crate::{get_manifest, merge_files};
-use getset::Getters;
+use std::ops::RangeInclusive;
+use std::path::{Component, Path};
+use crate::path::path::ZAPRETT_DIR_PATH;
+use crate::resolver::Resolver;
+use crate::get_manifest_content;
+use tokio::fs;
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use getset::{CopyGetters, Getters, Setters};
 use serde::{Deserialize, Serialize};
-use crate::path::path::MODULE_PATH;
 
 #[derive(Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -12,7 +17,7 @@ pub enum ListType {
     Blacklist,
 }
 
-#[derive(Default, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, ValueEnum)]
 #[serde(rename_all = "lowercase")]
 pub enum ServiceType {
     #[default]
@@ -29,6 +34,32 @@ pub enum ApplistType {
     Whitelist,
 }
 
+#[derive(Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+    #[default]
+    Both,
+}
+
+#[derive(Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallType {
+    #[default]
+    Auto,
+    Iptables,
+    Nftables,
+}
+
+#[derive(Serialize, Deserialize, CopyGetters, Clone, Copy)]
+#[getset(get_copy = "pub")]
+#[serde(default)]
+pub struct QueueConfig {
+    num: u16,
+    count: u16,
+}
+
 #[derive(Default, Serialize, Deserialize, Getters)]
 #[getset(get = "pub")]
 #[serde(default)]
@@ -44,6 +75,25 @@ pub struct Config {
     app_list: ApplistType,
     whitelist: Vec<String>,
     blacklist: Vec<String>,
+    ip_family: IpFamily,
+    firewall: FirewallType,
+    queue: QueueConfig,
+    stop_timeout: Option<u64>,
+    restart_limit: Option<u32>,
+    #[getset(skip)]
+    instances: Vec<Instance>,
+}
+
+/// One engine process tree with its own strategy and queues. Its name keys the
+/// pid file and logs in `tmp`.
+#[derive(Default, Serialize, Deserialize, Getters, Setters, Clone)]
+#[getset(get = "pub", set = "pub")]
+#[serde(default)]
+pub struct Instance {
+    name: String,
+    service_type: ServiceType,
+    strategy: String,
+    queue: QueueConfig,
 }
 
 #[derive(Serialize, Deserialize, Getters)]
@@ -56,15 +106,148 @@ pub struct Manifest {
     author: String,
     description: String,
     dependencies: Vec<String>,
-    file: String
+    file: String,
+    /// Expected hex SHA-256 of `file`, checked before the file is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// Expected size of `file` in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+/// Newest manifest schema this build understands.
+pub const MANIFEST_SCHEMA: i32 = 1;
+
+/// Manifest formats by their `schema` number. Older formats are upgraded into
+/// the current [`Manifest`] model after parsing.
+pub enum VersionedManifest {
+    V1(Manifest),
+}
+
+impl VersionedManifest {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        #[derive(Deserialize)]
+        struct Schema {
+            schema: Option<i32>,
+        }
+        let Schema { schema } = serde_json::from_str(content)?;
+        let Some(schema) = schema else {
+            bail!("missing schema, the newest supported is {MANIFEST_SCHEMA}");
+        };
+        match schema {
+            1 => Ok(VersionedManifest::V1(serde_json::from_str(content)?)),
+            _ => bail!("unsupported schema {schema}, newest supported is {MANIFEST_SCHEMA}"),
+        }
+    }
+
+    pub fn upgrade(self) -> Manifest {
+        match self {
+            VersionedManifest::V1(manifest) => manifest,
+        }
+    }
+}
+
+impl Manifest {
+    /// Makes `file` and path dependencies absolute. Paths starting with `./` or
+    /// `../` are relative to `manifest_dir`, other relative paths to the zaprett dir.
+    pub(crate) fn resolve_paths(&mut self, manifest_dir: &Path) {
+        self.file = resolve_path(&self.file, manifest_dir);
+        for dependency in &mut self.dependencies {
+            if dependency.contains('/') {
+                *dependency = resolve_path(dependency, manifest_dir);
+            }
+        }
+    }
+}
+
+fn resolve_path(path: &str, manifest_dir: &Path) -> String {
+    let relative = Path::new(path);
+    if path.is_empty() || relative.is_absolute() {
+        return path.to_string();
+    }
+    let base = match relative.components().next() {
+        Some(Component::CurDir | Component::ParentDir) => manifest_dir,
+        _ => *ZAPRETT_DIR_PATH,
+    };
+    base.join(relative).to_string_lossy().into_owned()
+}
+
+impl ServiceType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServiceType::Nfqws => "nfqws",
+            ServiceType::Nfqws2 => "nfqws2",
+        }
+    }
+}
+
+impl Config {
+    /// The top-level strategy used for `service_type` when no instances are configured.
+    pub fn strategy_for(&self, service_type: ServiceType) -> &String {
+        match service_type {
+            ServiceType::Nfqws => &self.strategy,
+            ServiceType::Nfqws2 => &self.strategy_nfqws2,
+        }
+    }
+
+    /// The configured engine instances. Configs without `instances` run a
+    /// single one built from the top-level `service_type`, strategy and queue.
+    pub fn instances(&self) -> anyhow::Result<Vec<Instance>> {
+        if self.instances.is_empty() {
+            return Ok(vec![Instance {
+                name: self.service_type.name().to_string(),
+                service_type: self.service_type,
+                strategy: self.strategy_for(self.service_type).clone(),
+                queue: self.queue,
+            }]);
+        }
+
+        for (i, instance) in self.instances.iter().enumerate() {
+            if instance.name.is_empty()
+                || !instance.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                bail!("Invalid instance name \"{}\"", instance.name);
+            }
+            for other in &self.instances[..i] {
+                if other.name == instance.name {
+                    bail!("Duplicate instance name \"{}\"", instance.name);
+                }
+                let (queues, other_queues) = (instance.queue.queues(), other.queue.queues());
+                if queues.start() <= other_queues.end() && other_queues.start() <= queues.end() {
+                    bail!("Instances \"{}\" and \"{}\" share queues", other.name, instance.name);
+                }
+            }
+        }
+        Ok(self.instances.clone())
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self { num: 200, count: 1 }
+    }
+}
+
+impl QueueConfig {
+    /// Every queue the engine workers listen on, one worker per queue.
+    pub fn queues(&self) -> RangeInclusive<u16> {
+        self.num..=self.num.saturating_add(self.count.max(1) - 1)
+    }
 }
 
 impl ListType {
+    /// Merges the active lists into `tmp_dir`, or only checks them without `write`.
+    ///
     /// # Returns
     ///
     /// (hostlist arg, ipset arg)
-    pub async fn merge(&self, config: &Config) -> anyhow::Result<(String, String)> {
-        let module_path_str = MODULE_PATH.to_str().unwrap();
+    pub async fn merge(
+        &self,
+        config: &Config,
+        resolver: &Resolver,
+        tmp_dir: &Path,
+        write: bool,
+    ) -> anyhow::Result<(String, String)> {
 
         let (host_files, ipset_files, host_suffix, ipset_suffix, exclude_flag) = match self {
             ListType::Whitelist => (
@@ -82,28 +265,72 @@ impl ListType {
                 "-exclude",
             ),
         };
-        let host_paths: Vec<PathBuf> = host_files.iter()
-            .map(|path| -> anyhow::Result<PathBuf> {
-                let manifest = get_manifest(Path::new(path))?;
-                Ok(PathBuf::from(manifest.file()))
-            }).collect::<anyhow::Result<_>>()?;
-        let ipset_paths: Vec<PathBuf> = ipset_files
-            .iter()
-            .map(|path| -> anyhow::Result<PathBuf> {
-                let manifest = get_manifest(Path::new(path))?;
-                Ok(PathBuf::from(manifest.file()))
-            })
-            .collect::<anyhow::Result<_>>()?;
-
-        let host_path = MODULE_PATH.join(format!("tmp/{host_suffix}"));
-        let ipset_path = MODULE_PATH.join(format!("tmp/{ipset_suffix}"));
-
-        merge_files(&host_paths, host_path).await?;
-        merge_files(&ipset_paths, ipset_path).await?;
+        // Lists are verified once and the verified bytes are what gets
+        // written, so a file swapped after its check is never used.
+        let hosts = read_lists(host_files, resolver)?;
+        let ipsets = read_lists(ipset_files, resolver)?;
+
+        let host_path = tmp_dir.join(host_suffix);
+        let ipset_path = tmp_dir.join(ipset_suffix);
+
+        if write {
+            fs::write(&host_path, hosts)
+                .await
+                .with_context(|| format!("Failed to write {}", host_path.display()))?;
+            fs::write(&ipset_path, ipsets)
+                .await
+                .with_context(|| format!("Failed to write {}", ipset_path.display()))?;
+        }
 
         Ok((
-            format!("--hostlist{exclude_flag}={module_path_str}/tmp/{host_suffix}"),
-            format!("--ipset{exclude_flag}={module_path_str}/tmp/{ipset_suffix}"),
+            format!("--hostlist{exclude_flag}={}", host_path.display()),
+            format!("--ipset{exclude_flag}={}", ipset_path.display()),
         ))
     }
-}
\ No newline at end of file
+}
+
+/// Concatenates the files of the list manifests at `paths`.
+fn read_lists(paths: &[String], resolver: &Resolver) -> anyhow::Result<Vec<u8>> {
+    let mut merged = Vec::new();
+    for path in paths {
+        let (_, content) = get_manifest_content(Path::new(path), resolver)?;
+        merged.extend_from_slice(&content);
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_current_manifest_schema() {
+        let manifest = r#"{"schema": 1, "id": "a", "name": "a", "version": "1.0.0", "author": "",
+            "description": "", "dependencies": [], "file": "a.txt"}"#;
+        assert_eq!(VersionedManifest::parse(manifest).unwrap().upgrade().id(), "a");
+    }
+
+    #[test]
+    fn rejects_newer_manifest_schemas() {
+        let error = VersionedManifest::parse(r#"{"schema": 2, "id": "a"}"#).err().unwrap();
+        assert_eq!(error.to_string(), "unsupported schema 2, newest supported is 1");
+    }
+
+    #[test]
+    fn rejects_manifests_without_schema() {
+        let error = VersionedManifest::parse(r#"{"id": "a"}"#).err().unwrap();
+        assert_eq!(error.to_string(), "missing schema, the newest supported is 1");
+    }
+
+    #[test]
+    fn resolves_relative_manifest_paths() {
+        let dir = Path::new("/manifests/bin");
+        assert_eq!(resolve_path("/abs/file.txt", dir), "/abs/file.txt");
+        assert_eq!(resolve_path("./x.bin", dir), "/manifests/bin/./x.bin");
+        assert_eq!(resolve_path("../x.bin", dir), "/manifests/bin/../x.bin");
+        assert_eq!(
+            resolve_path("files/bin/x.bin", dir),
+            ZAPRETT_DIR_PATH.join("files/bin/x.bin").to_string_lossy()
+        );
+    }
+}
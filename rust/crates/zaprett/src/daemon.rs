@@ -1,49 +1,391 @@
+use crate::applist::AppFilter;
+use crate::config::{Config, Instance, ServiceType};
+use crate::firewall::{instance_rules, Rule};
+use crate::strategy::Strategy;
+use crate::lock::{close_inherited_lock, PidFile};
 use crate::{run_nfqws, run_nfqws2};
-use daemonize::Daemonize;
-use log::{error, info};
-use std::fs::File;
+use anyhow::bail;
+use daemonize::{Daemonize, Outcome};
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::sys::signal::{SigSet, Signal, kill};
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+use nix::unistd::{ForkResult, fork, setpgid, Pid};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::process::exit;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 use crate::path::path::MODULE_PATH;
 
-pub async fn daemonize_nfqws(args: &str) {
-    info!("Starting nfqws as a daemon");
+const DEFAULT_RESTART_LIMIT: u32 = 5;
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// A worker that ran this long before failing starts its backoff from scratch.
+const STABLE_RUN: Duration = Duration::from_secs(300);
+/// A worker failing this soon after the supervisor started is misconfigured
+/// (typically a bad strategy argument), so restarting it is pointless.
+pub const STARTUP_GRACE: Duration = Duration::from_millis(500);
+/// How long old workers get to release their queues during a reload.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest the supervisor waits for a signal before looking at its workers
+/// again, in case a SIGCHLD was taken by another thread.
+const MAX_WAIT: Duration = Duration::from_secs(1);
 
-    let stdout = File::create(MODULE_PATH.join("tmp/nfqws.out")).unwrap();
-    let stderr = File::create(MODULE_PATH.join("tmp/nfqws.err")).unwrap();
+/// Everything the supervisor needs to run one engine instance.
+pub struct EngineSpec {
+    pub name: String,
+    pub service_type: ServiceType,
+    pub args: Vec<String>,
+    pub queues: RangeInclusive<u16>,
+    pub restart_limit: u32,
+    /// The firewall rules the instance needs, to tell whether a reload can keep the installed ones.
+    pub rules: Vec<Rule>,
+}
 
-    let daemonize = Daemonize::new()
-        .pid_file(MODULE_PATH.join("tmp/pid.lock").as_path())
-        .working_directory(MODULE_PATH.join("tmp"))
-        .stdout(stdout)
-        .stderr(stderr)
-        .privileged_action(|| "Executed before drop privileges");
+type Workers = HashMap<Pid, (u16, Instant)>;
+
+impl EngineSpec {
+    pub fn new(config: &Config, instance: &Instance, strategy: Strategy, apps: &AppFilter) -> Self {
+        Self {
+            name: instance.name().clone(),
+            service_type: *instance.service_type(),
+            rules: instance_rules(config, instance, &strategy, apps),
+            args: strategy.args,
+            queues: instance.queue().queues(),
+            restart_limit: config.restart_limit().unwrap_or(DEFAULT_RESTART_LIMIT),
+        }
+    }
+
+    fn run(&self, qnum: u16) -> anyhow::Result<()> {
+        match self.service_type {
+            ServiceType::Nfqws => run_nfqws(&self.args, qnum),
+            ServiceType::Nfqws2 => run_nfqws2(&self.args, qnum),
+        }
+    }
+}
+
+pub fn restart_count(name: &str) -> u32 {
+    fs::read_to_string(MODULE_PATH.join(format!("tmp/{name}.restarts")))
+        .ok()
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn record_restarts(name: &str, count: u32) {
+    if let Err(e) = fs::write(MODULE_PATH.join(format!("tmp/{name}.restarts")), count.to_string()) {
+        warn!("Failed to record restart count: {e}");
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    BACKOFF_BASE
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(BACKOFF_MAX)
+}
+
+/// Blocks the signals the supervisor waits for on the calling thread, and on
+/// threads it spawns later. Blocked, they stay pending until [`wait_signal`]
+/// takes them, so none is lost between two waits.
+pub fn block_signals() -> nix::Result<SigSet> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGHUP);
+    signals.add(Signal::SIGCHLD);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    signals.thread_block()?;
+    Ok(signals)
+}
+
+/// Waits for one of the blocked `signals`, or until `timeout` passes.
+fn wait_signal(signals: &SigSet, timeout: Option<Duration>) -> nix::Result<Option<Signal>> {
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timeout = timeout.as_ref().map_or(std::ptr::null(), |timeout| timeout as *const _);
+    let signal = unsafe { libc::sigtimedwait(signals.as_ref(), std::ptr::null_mut(), timeout) };
+    if signal < 0 {
+        return match Errno::last() {
+            Errno::EAGAIN | Errno::EINTR => Ok(None),
+            e => Err(e),
+        };
+    }
+    Signal::try_from(signal).map(Some)
+}
+
+/// Collects one exited worker, if any.
+fn reap(workers: &mut Workers) -> anyhow::Result<Option<(WaitStatus, u16, Instant)>> {
+    loop {
+        let status = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(Errno::ECHILD) => return Ok(None),
+            Ok(status) => status,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some((qnum, started)) = status.pid().and_then(|pid| workers.remove(&pid)) {
+            return Ok(Some((status, qnum, started)));
+        }
+    }
+}
+
+fn spawn_worker(qnum: u16, spec: &EngineSpec) -> anyhow::Result<Pid> {
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            if let Err(e) = SigSet::all().thread_unblock() {
+                error!("Failed to unblock signals: {e}");
+            }
+            if let Err(e) = spec.run(qnum) {
+                error!("Worker for queue {qnum} failed: {e}");
+                exit(1);
+            }
+            exit(0);
+        }
+        ForkResult::Parent { child } => {
+            info!("Started worker {child} on queue {qnum}");
+            Ok(child)
+        }
+    }
+}
+
+fn spawn_workers(spec: &EngineSpec, workers: &mut Workers) -> anyhow::Result<()> {
+    for qnum in spec.queues.clone() {
+        workers.insert(spawn_worker(qnum, spec)?, (qnum, Instant::now()));
+    }
+    Ok(())
+}
 
-    match daemonize.start() {
+fn stop_workers(workers: &Workers) {
+    for pid in workers.keys() {
+        let _ = kill(*pid, Signal::SIGTERM);
+    }
+}
+
+/// Terminates the current workers and waits for them, so their queues are
+/// free to bind again. Stragglers are killed after [`RELOAD_TIMEOUT`].
+fn drain_workers(workers: &mut Workers) {
+    stop_workers(workers);
+    let deadline = Instant::now() + RELOAD_TIMEOUT;
+    while !workers.is_empty() {
+        if Instant::now() >= deadline {
+            for pid in workers.keys() {
+                let _ = kill(*pid, Signal::SIGKILL);
+            }
+        }
+        workers.retain(|pid, _| {
+            matches!(waitpid(*pid, Some(WaitPidFlag::WNOHANG)), Ok(WaitStatus::StillAlive))
+        });
+        sleep(Duration::from_millis(50));
+    }
+}
+
+/// Replaces the workers with ones running the freshly loaded spec. The
+/// firewall is left untouched, so a spec needing other rules is refused.
+///
+/// `load(false)` only renders the new spec; the files the running workers
+/// read are rewritten by `load(true)` once the reload has been accepted.
+fn reload(
+    spec: &mut EngineSpec,
+    workers: &mut Workers,
+    load: &impl Fn(bool) -> anyhow::Result<EngineSpec>,
+) -> anyhow::Result<()> {
+    let new_spec = match load(false) {
+        Ok(new_spec) => new_spec,
+        Err(e) => {
+            error!("Reload failed, keeping the running engine: {e:#}");
+            return Ok(());
+        }
+    };
+    if new_spec.rules != spec.rules {
+        error!(
+            "Reload needs other firewall rules (queues, strategy ports, app list or ip family \
+             changed), keeping the running engine; restart the service to apply it"
+        );
+        return Ok(());
+    }
+    let new_spec = match load(true) {
+        Ok(new_spec) if new_spec.rules == spec.rules => new_spec,
         Ok(_) => {
-            info!("Success, daemonized");
-            run_nfqws(args).unwrap()
+            error!("Config changed during the reload, keeping the running engine");
+            return Ok(());
+        }
+        Err(e) => {
+            error!("Reload failed, keeping the running engine: {e:#}");
+            return Ok(());
+        }
+    };
+    info!("Reloading {} with the new strategy", new_spec.name);
+    drain_workers(workers);
+    *spec = new_spec;
+    spawn_workers(spec, workers)
+}
+
+/// Forks one engine worker per queue and restarts workers that exit abnormally,
+/// backing off exponentially and giving up after `restart_limit` consecutive failures.
+/// On SIGHUP the workers are swapped for ones running the spec returned by
+/// `load`, which writes the files of the spec only when passed `true`. On
+/// SIGTERM or SIGINT the workers are stopped and the supervisor returns.
+pub fn supervise(
+    mut spec: EngineSpec,
+    load: impl Fn(bool) -> anyhow::Result<EngineSpec>,
+) -> anyhow::Result<()> {
+    let signals = block_signals()?;
+
+    let mut workers = Workers::new();
+    // Queues waiting out their backoff, with the time their worker is due again.
+    let mut pending: Vec<(Instant, u16)> = Vec::new();
+    let mut failures: HashMap<u16, u32> = HashMap::new();
+    let mut restarts = 0;
+    record_restarts(&spec.name, restarts);
+    let supervisor_started = Instant::now();
+
+    spawn_workers(&spec, &mut workers)?;
+
+    loop {
+        let now = Instant::now();
+        for (_, qnum) in pending.extract_if(.., |(due, _)| *due <= now) {
+            workers.insert(spawn_worker(qnum, &spec)?, (qnum, Instant::now()));
+            restarts += 1;
+            record_restarts(&spec.name, restarts);
+        }
+
+        let Some((status, qnum, started)) = reap(&mut workers)? else {
+            if workers.is_empty() && pending.is_empty() {
+                break;
+            }
+            let timeout = pending
+                .iter()
+                .map(|(due, _)| due.saturating_duration_since(now))
+                .fold(MAX_WAIT, Duration::min);
+            match wait_signal(&signals, Some(timeout))? {
+                Some(Signal::SIGHUP) => {
+                    // The reload starts a worker on every queue, backing off ones included.
+                    reload(&mut spec, &mut workers, &load)?;
+                    pending.clear();
+                    failures.clear();
+                }
+                Some(signal @ (Signal::SIGTERM | Signal::SIGINT)) => {
+                    info!("Stopping {} on {signal}", spec.name);
+                    drain_workers(&mut workers);
+                    return Ok(());
+                }
+                _ => {}
+            }
+            continue;
+        };
+        match status {
+            WaitStatus::Exited(_, 0) | WaitStatus::Signaled(_, Signal::SIGTERM | Signal::SIGINT, _) => {
+                info!("Worker on queue {qnum} exited");
+                continue;
+            }
+            _ => warn!("Worker on queue {qnum} died: {status:?}"),
         }
-        Err(e) => error!("Error while starting nfqws daemon: {e}"),
+
+        if restarts == 0 && supervisor_started.elapsed() < STARTUP_GRACE {
+            stop_workers(&workers);
+            bail!("Worker on queue {qnum} exited right after start");
+        }
+
+        let failures = failures.entry(qnum).or_default();
+        if started.elapsed() >= STABLE_RUN {
+            *failures = 0;
+        }
+        *failures += 1;
+        if *failures > spec.restart_limit {
+            stop_workers(&workers);
+            bail!(
+                "Worker on queue {qnum} keeps failing, giving up after {} restarts",
+                spec.restart_limit
+            );
+        }
+
+        let delay = backoff(*failures);
+        info!("Restarting worker on queue {qnum} in {}s", delay.as_secs());
+        pending.push((Instant::now() + delay, qnum));
     }
+    Ok(())
+}
+
+pub fn pid_path(name: &str) -> PathBuf {
+    MODULE_PATH.join(format!("tmp/{name}.pid"))
 }
 
-pub async fn daemonize_nfqws2(args: &str) {
-    info!("Starting nfqws2 as a daemon");
+pub async fn daemonize_engine(
+    spec: EngineSpec,
+    load: impl Fn(bool) -> anyhow::Result<EngineSpec>,
+) -> anyhow::Result<()> {
+    let name = spec.name.clone();
+    info!("Starting {name} as a daemon");
 
-    let stdout = File::create(MODULE_PATH.join("tmp/nfqws2.out")).unwrap();
-    let stderr = File::create(MODULE_PATH.join("tmp/nfqws2.err")).unwrap();
+    let stdout = File::create(MODULE_PATH.join(format!("tmp/{name}.out")))?;
+    let stderr = File::create(MODULE_PATH.join(format!("tmp/{name}.err")))?;
 
     let daemonize = Daemonize::new()
-        .pid_file(MODULE_PATH.join("tmp/pid.lock").as_path())
         .working_directory(MODULE_PATH.join("tmp"))
         .stdout(stdout)
         .stderr(stderr)
         .privileged_action(|| "Executed before drop privileges");
 
-    match daemonize.start() {
-        Ok(_) => {
-            info!("Success, nfqws2 daemonized");
-            run_nfqws2(args).unwrap()
+    match daemonize.execute() {
+        Outcome::Parent(Ok(_)) => Ok(()),
+        Outcome::Parent(Err(e)) => bail!("Error while starting {name} daemon: {e}"),
+        Outcome::Child(Ok(_)) => {
+            info!("Success, {name} daemonized");
+            close_inherited_lock();
+            // Leading its own process group, the daemon stops with every worker on killpg.
+            if let Err(e) = setpgid(Pid::from_raw(0), Pid::from_raw(0)) {
+                error!("Failed to create process group: {e}");
+            }
+            if let Err(e) = PidFile::current(spec.service_type)
+                .and_then(|pid_file| pid_file.write(&pid_path(&name)))
+            {
+                error!("{e:#}");
+                exit(1)
+            }
+            if let Err(e) = supervise(spec, load) {
+                error!("{name} supervisor stopped: {e}");
+                exit(1)
+            }
+            exit(0)
         }
-        Err(e) => error!("Error while starting nfqws2 daemon: {e}"),
+        Outcome::Child(Err(e)) => {
+            error!("Error while starting {name} daemon: {e}");
+            exit(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tokenize;
+    use nix::sys::signal::raise;
+
+    fn spec(strategy: &str) -> EngineSpec {
+        let args = tokenize(strategy).unwrap().into_iter().map(|token| token.value).collect();
+        let strategy = Strategy::parse(ServiceType::Nfqws, args).unwrap();
+        EngineSpec::new(&Config::default(), &Instance::default(), strategy, &AppFilter::All)
+    }
+
+    #[test]
+    fn reload_needing_other_rules_is_refused() {
+        let mut running = spec("--filter-tcp=443 --dpi-desync=fake");
+        let load = |write: bool| {
+            assert!(!write, "a refused reload must not write its files");
+            Ok(spec("--filter-tcp=80 --dpi-desync=fake"))
+        };
+        reload(&mut running, &mut Workers::new(), &load).unwrap();
+        assert_eq!(running.args[0], "--filter-tcp=443");
+    }
+
+    #[test]
+    fn signal_raised_before_waiting_is_kept() {
+        let signals = block_signals().unwrap();
+        raise(Signal::SIGHUP).unwrap();
+        let wait = |ms| wait_signal(&signals, Some(Duration::from_millis(ms))).unwrap();
+        assert_eq!(wait(100), Some(Signal::SIGHUP));
+        assert_eq!(wait(10), None);
     }
 }
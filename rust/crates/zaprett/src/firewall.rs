@@ -0,0 +1,337 @@
+use crate::applist::AppFilter;
+use crate::config::{Config, FirewallType, Instance, IpFamily};
+use crate::iptables_rust::{Iptables, iptables_available};
+use crate::nftables_rust::{Nftables, nftables_available};
+use crate::strategy::{PortFilters, PortSet, Strategy};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    Prerouting,
+    Postrouting,
+    Forward,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Limits a rule to one protocol and, unless `ports` is empty, to service ports:
+/// destination ports of outgoing packets, source ports of replies.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortMatch {
+    pub protocol: Protocol,
+    pub ports: Vec<RangeInclusive<u16>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Queue,
+    Return,
+}
+
+/// A single NFQUEUE interception rule, independent of the backend that installs it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub family: Family,
+    pub hook: Hook,
+    pub queues: RangeInclusive<u16>,
+    pub uid: Option<u32>,
+    pub ports: Option<PortMatch>,
+    /// Only the first packets of a connection, counted in the direction of the hook.
+    pub packets: Option<RangeInclusive<u32>>,
+    pub target: Target,
+}
+
+/// How many packets of each connection the engine sees when rules are scoped to ports.
+const ORIGINAL_PACKETS: RangeInclusive<u32> = 1..=6;
+const REPLY_PACKETS: RangeInclusive<u32> = 1..=3;
+
+/// iptables multiport accepts up to 15 ports per rule, a range counting as two.
+const MULTIPORT_LIMIT: usize = 15;
+
+pub trait FirewallBackend {
+    fn install(&self, rules: &[Rule]) -> anyhow::Result<()>;
+    /// Tears down every rule the backend owns for `families`.
+    fn remove(&self, families: &[Family]) -> anyhow::Result<()>;
+    fn list(&self) -> anyhow::Result<Vec<String>>;
+}
+
+impl Family {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Family::Ipv4 => "ipv4",
+            Family::Ipv6 => "ipv6",
+        }
+    }
+}
+
+impl IpFamily {
+    pub fn families(&self) -> &'static [Family] {
+        match self {
+            IpFamily::Ipv4 => &[Family::Ipv4],
+            IpFamily::Ipv6 => &[Family::Ipv6],
+            IpFamily::Both => &[Family::Ipv4, Family::Ipv6],
+        }
+    }
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+impl Hook {
+    /// Whether packets in this hook travel in the reply direction of a connection.
+    pub fn is_reply(&self) -> bool {
+        matches!(self, Hook::Prerouting)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hook::Prerouting => "prerouting",
+            Hook::Postrouting => "postrouting",
+            Hook::Forward => "forward",
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.family.name(), self.hook.name())?;
+        if let Some(uid) = self.uid {
+            write!(f, " uid {uid}")?;
+        }
+        if let Some(ports) = &self.ports {
+            write!(f, " {}", ports.protocol.name())?;
+            if !ports.ports.is_empty() {
+                let ports: Vec<String> = ports
+                    .ports
+                    .iter()
+                    .map(|range| format!("{}-{}", range.start(), range.end()))
+                    .collect();
+                write!(f, " ports {}", ports.join(","))?;
+            }
+        }
+        if let Some(packets) = &self.packets {
+            write!(f, " packets {}-{}", packets.start(), packets.end())?;
+        }
+        match self.target {
+            Target::Queue => write!(f, " queue {}-{}", self.queues.start(), self.queues.end()),
+            Target::Return => write!(f, " return"),
+        }
+    }
+}
+
+fn chunk_ports(ports: &[RangeInclusive<u16>]) -> Vec<Vec<RangeInclusive<u16>>> {
+    let mut chunks: Vec<Vec<RangeInclusive<u16>>> = vec![Vec::new()];
+    let mut weight = 0;
+    for range in ports {
+        let cost = if range.start() == range.end() { 1 } else { 2 };
+        if weight + cost > MULTIPORT_LIMIT {
+            chunks.push(Vec::new());
+            weight = 0;
+        }
+        chunks.last_mut().unwrap().push(range.clone());
+        weight += cost;
+    }
+    chunks
+}
+
+/// Splits a catch-all queue rule into rules covering only the filtered ports
+/// and the first packets of each connection.
+fn scope(rule: Rule, filters: &PortFilters) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for (protocol, ports) in [(Protocol::Tcp, &filters.tcp), (Protocol::Udp, &filters.udp)] {
+        let chunks = match ports {
+            None => continue,
+            Some(PortSet::Any) => vec![Vec::new()],
+            Some(PortSet::Ports(ports)) => chunk_ports(ports),
+        };
+        for ports in chunks {
+            rules.push(Rule {
+                ports: Some(PortMatch { protocol, ports }),
+                packets: Some(if rule.hook.is_reply() {
+                    REPLY_PACKETS
+                } else {
+                    ORIGINAL_PACKETS
+                }),
+                ..rule.clone()
+            });
+        }
+    }
+    rules
+}
+
+/// Builds the interception rules sending traffic to `queues`. Without `filters`
+/// every packet is queued, otherwise queue rules are narrowed to the strategy's ports.
+pub fn rules_for(
+    config: &Config,
+    queues: RangeInclusive<u16>,
+    apps: &AppFilter,
+    filters: Option<&PortFilters>,
+) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for &family in config.ip_family().families() {
+        let rule = |hook, uid, target| Rule {
+            family,
+            hook,
+            queues: queues.clone(),
+            uid,
+            ports: None,
+            packets: None,
+            target,
+        };
+        // Owner matching only works on locally generated packets, so the app
+        // list narrows POSTROUTING. Replies keep going through PREROUTING as the
+        // engine needs them to track the connections it desyncs.
+        match apps {
+            AppFilter::All => rules.push(rule(Hook::Postrouting, None, Target::Queue)),
+            AppFilter::Only(uids) => rules.extend(
+                uids.iter()
+                    .map(|uid| rule(Hook::Postrouting, Some(*uid), Target::Queue)),
+            ),
+            AppFilter::Except(uids) => {
+                rules.extend(
+                    uids.iter()
+                        .map(|uid| rule(Hook::Postrouting, Some(*uid), Target::Return)),
+                );
+                rules.push(rule(Hook::Postrouting, None, Target::Queue));
+            }
+        }
+        rules.push(rule(Hook::Prerouting, None, Target::Queue));
+        // Forwarded (tethered) traffic belongs to no app, so a whitelist excludes it.
+        if !matches!(apps, AppFilter::Only(_)) {
+            rules.push(rule(Hook::Forward, None, Target::Queue));
+        }
+    }
+    match filters {
+        Some(filters) => rules
+            .into_iter()
+            .flat_map(|rule| match rule.target {
+                Target::Queue => scope(rule, filters),
+                Target::Return => vec![rule],
+            })
+            .collect(),
+        None => rules,
+    }
+}
+
+/// The rules one engine instance needs for its rendered strategy.
+pub fn instance_rules(
+    config: &Config,
+    instance: &Instance,
+    strategy: &Strategy,
+    apps: &AppFilter,
+) -> Vec<Rule> {
+    rules_for(config, instance.queue().queues(), apps, strategy.port_filters().as_ref())
+}
+
+/// The backend and families the rules were installed with. Saved on start so
+/// stop and status still find the rules after `firewall` or `ip_family` change.
+#[derive(Serialize, Deserialize)]
+pub struct FirewallState {
+    pub firewall: FirewallType,
+    pub ip_family: IpFamily,
+}
+
+impl FirewallState {
+    /// Resolves the backend `config` asks for, picking one for `auto`.
+    pub fn new(config: &Config) -> Self {
+        let firewall = match config.firewall() {
+            FirewallType::Auto
+                if !iptables_available(config.ip_family()) && nftables_available() =>
+            {
+                FirewallType::Nftables
+            }
+            FirewallType::Auto => FirewallType::Iptables,
+            firewall => *firewall,
+        };
+        Self {
+            firewall,
+            ip_family: *config.ip_family(),
+        }
+    }
+
+    pub fn backend(&self) -> Box<dyn FirewallBackend> {
+        match self.firewall {
+            FirewallType::Nftables => Box::new(Nftables),
+            FirewallType::Auto | FirewallType::Iptables => Box::new(Iptables),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Option<Self> {
+        serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+}
+
+/// Keeps rules in memory so tests can check what a backend would have been asked to do.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingFirewall {
+    installed: std::sync::Mutex<Vec<Rule>>,
+}
+
+#[cfg(test)]
+impl RecordingFirewall {
+    pub fn installed(&self) -> Vec<Rule> {
+        self.installed.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl FirewallBackend for RecordingFirewall {
+    fn install(&self, rules: &[Rule]) -> anyhow::Result<()> {
+        self.installed.lock().unwrap().extend_from_slice(rules);
+        Ok(())
+    }
+
+    fn remove(&self, families: &[Family]) -> anyhow::Result<()> {
+        self.installed
+            .lock()
+            .unwrap()
+            .retain(|installed| !families.contains(&installed.family));
+        Ok(())
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.installed().iter().map(Rule::to_string).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_ports_at_the_multiport_limit() {
+        let ports: Vec<RangeInclusive<u16>> = (1..=14).map(|port| port..=port).collect();
+        let with_range = [ports.clone(), vec![100..=200]].concat();
+        assert_eq!(chunk_ports(&with_range), [ports.clone(), vec![100..=200]]);
+
+        let with_port = [ports.clone(), vec![15..=15, 16..=16]].concat();
+        assert_eq!(chunk_ports(&with_port), [[ports, vec![15..=15]].concat(), vec![16..=16]]);
+    }
+}
@@ -1,81 +1,271 @@
-use std::process::Command;
-
-pub fn setup_iptables_rules() -> anyhow::Result<()> {
-    Command::new("iptables")
-        .arg("-t")
-        .arg("mangle")
-        .arg("-I")
-        .arg("POSTROUTING")
-        .arg("-j")
-        .arg("NFQUEUE")
-        .arg("--queue-num")
-        .arg("200")
-        .arg("--queue-bypass")
-        .status()?;
-
-    Command::new("iptables")
-        .arg("-t")
-        .arg("mangle")
-        .arg("-I")
-        .arg("PREROUTING")
-        .arg("-j")
-        .arg("NFQUEUE")
-        .arg("--queue-num")
-        .arg("200")
-        .arg("--queue-bypass")
-        .status()?;
-
-    Command::new("iptables")
-        .arg("-t")
-        .arg("filter")
-        .arg("-A")
-        .arg("FORWARD")
-        .arg("-j")
-        .arg("NFQUEUE")
-        .arg("--queue-num")
-        .arg("200")
-        .arg("--queue-bypass")
-        .status()?;
+use crate::config::IpFamily;
+use crate::firewall::{Family, FirewallBackend, Hook, Rule, Target};
+use anyhow::{Context, bail};
+use std::process::{Command, Stdio};
 
+pub struct Iptables;
+
+const HOOKS: [Hook; 3] = [Hook::Postrouting, Hook::Prerouting, Hook::Forward];
+
+/// Waits for the xtables lock instead of failing while another process,
+/// such as netd on Android, holds it.
+const WAIT: &str = "-w";
+
+fn binary(family: Family) -> &'static str {
+    match family {
+        Family::Ipv4 => "iptables",
+        Family::Ipv6 => "ip6tables",
+    }
+}
+
+/// (table, built-in chain, our chain, jump action)
+fn chains(hook: Hook) -> (&'static str, &'static str, &'static str, &'static str) {
+    match hook {
+        Hook::Postrouting => ("mangle", "POSTROUTING", "ZAPRETT_POSTROUTING", "-I"),
+        Hook::Prerouting => ("mangle", "PREROUTING", "ZAPRETT_PREROUTING", "-I"),
+        Hook::Forward => ("filter", "FORWARD", "ZAPRETT_FORWARD", "-A"),
+    }
+}
+
+pub fn iptables_available(family: &IpFamily) -> bool {
+    family.families().iter().all(|family| {
+        Command::new(binary(*family))
+            .args([WAIT, "-V"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    })
+}
+
+fn run(binary: &str, args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new(binary)
+        .arg(WAIT)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {binary}"))?;
+    if !status.success() {
+        bail!("{binary} {} failed: {status}", args.join(" "));
+    }
     Ok(())
 }
 
-pub fn clear_iptables_rules() -> anyhow::Result<()> {
-    Command::new("iptables")
-        .arg("-t")
-        .arg("mangle")
-        .arg("-D")
-        .arg("POSTROUTING")
-        .arg("-j")
-        .arg("NFQUEUE")
-        .arg("--queue-num")
-        .arg("200")
-        .arg("--queue-bypass")
-        .status()?;
-
-    Command::new("iptables")
-        .arg("-t")
-        .arg("mangle")
-        .arg("-D")
-        .arg("PREROUTING")
-        .arg("-j")
-        .arg("NFQUEUE")
-        .arg("--queue-num")
-        .arg("200")
-        .arg("--queue-bypass")
-        .status()?;
-
-    Command::new("iptables")
-        .arg("-t")
-        .arg("filter")
-        .arg("-D")
-        .arg("FORWARD")
-        .arg("-j")
-        .arg("NFQUEUE")
-        .arg("--queue-num")
-        .arg("200")
-        .arg("--queue-bypass")
-        .status()?;
+/// Runs a command whose failure is an expected answer (`-C`, `-N` on an existing chain, ...).
+fn probe(binary: &str, args: &[&str]) -> bool {
+    Command::new(binary)
+        .arg(WAIT)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn families(rules: &[Rule]) -> Vec<Family> {
+    let mut families: Vec<Family> = Vec::new();
+    for rule in rules {
+        if !families.contains(&rule.family) {
+            families.push(rule.family);
+        }
+    }
+    families
+}
+
+fn rule_spec(rule: &Rule) -> Vec<String> {
+    let mut spec = Vec::new();
+    if let Some(uid) = rule.uid {
+        spec.extend(["-m", "owner", "--uid-owner"].map(String::from));
+        spec.push(uid.to_string());
+    }
+    if let Some(ports) = &rule.ports {
+        spec.extend(["-p".to_string(), ports.protocol.name().to_string()]);
+        if !ports.ports.is_empty() {
+            let list: Vec<String> = ports
+                .ports
+                .iter()
+                .map(|range| match range.start() == range.end() {
+                    true => range.start().to_string(),
+                    false => format!("{}:{}", range.start(), range.end()),
+                })
+                .collect();
+            let direction = if rule.hook.is_reply() {
+                "--sports"
+            } else {
+                "--dports"
+            };
+            spec.extend(["-m", "multiport", direction].map(String::from));
+            spec.push(list.join(","));
+        }
+    }
+    if let Some(packets) = &rule.packets {
+        let direction = if rule.hook.is_reply() {
+            "reply"
+        } else {
+            "original"
+        };
+        spec.extend(["-m", "connbytes", "--connbytes-dir", direction].map(String::from));
+        spec.extend(["--connbytes-mode", "packets", "--connbytes"].map(String::from));
+        spec.push(format!("{}:{}", packets.start(), packets.end()));
+    }
+    match rule.target {
+        Target::Queue => {
+            spec.extend(["-j", "NFQUEUE"].map(String::from));
+            let (first, last) = (rule.queues.start(), rule.queues.end());
+            if first == last {
+                spec.extend(["--queue-num".to_string(), first.to_string()]);
+            } else {
+                spec.extend(["--queue-balance".to_string(), format!("{first}:{last}")]);
+            }
+            spec.push("--queue-bypass".to_string());
+        }
+        Target::Return => spec.extend(["-j", "RETURN"].map(String::from)),
+    }
+    spec
+}
+
+fn setup_chain(family: Family, hook: Hook, rules: &[&Rule]) -> anyhow::Result<()> {
+    let binary = binary(family);
+    let (table, builtin, chain, jump) = chains(hook);
 
+    if !probe(binary, &["-t", table, "-N", chain]) {
+        run(binary, &["-t", table, "-F", chain])?;
+    }
+    for rule in rules {
+        let mut args = vec!["-t", table, "-A", chain];
+        let spec = rule_spec(rule);
+        args.extend(spec.iter().map(String::as_str));
+        run(binary, &args)?;
+    }
+    if !probe(binary, &["-t", table, "-C", builtin, "-j", chain]) {
+        run(binary, &["-t", table, jump, builtin, "-j", chain])?;
+    }
     Ok(())
 }
+
+fn clear_chain(family: Family, hook: Hook) -> anyhow::Result<()> {
+    let binary = binary(family);
+    let (table, builtin, chain, _) = chains(hook);
+
+    // Drop every jump, including duplicates left by older versions or a double start.
+    while probe(binary, &["-t", table, "-C", builtin, "-j", chain]) {
+        run(binary, &["-t", table, "-D", builtin, "-j", chain])?;
+    }
+    if probe(binary, &["-t", table, "-S", chain]) {
+        run(binary, &["-t", table, "-F", chain])?;
+        run(binary, &["-t", table, "-X", chain])?;
+    }
+    Ok(())
+}
+
+impl FirewallBackend for Iptables {
+    fn install(&self, rules: &[Rule]) -> anyhow::Result<()> {
+        for family in families(rules) {
+            for hook in HOOKS {
+                let hook_rules: Vec<&Rule> = rules
+                    .iter()
+                    .filter(|rule| rule.family == family && rule.hook == hook)
+                    .collect();
+                if hook_rules.is_empty() {
+                    continue;
+                }
+                if let Err(e) = setup_chain(family, hook, &hook_rules) {
+                    // Leave no half-configured family behind: drop whatever was
+                    // created before reporting the error.
+                    let _ = self.remove(&families(rules));
+                    return Err(e.context(format!("Failed to set up {} rules", binary(family))));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, families: &[Family]) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for &family in families {
+            for hook in HOOKS {
+                if let Err(e) = clear_chain(family, hook) {
+                    result = result.and(Err(
+                        e.context(format!("Failed to clear {} rules", binary(family)))
+                    ));
+                }
+            }
+        }
+        result
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<String>> {
+        let mut rules = Vec::new();
+        for family in [Family::Ipv4, Family::Ipv6] {
+            for hook in HOOKS {
+                let (table, _, chain, _) = chains(hook);
+                let Ok(output) = Command::new(binary(family))
+                    .args([WAIT, "-t", table, "-S", chain])
+                    .stderr(Stdio::null())
+                    .output()
+                else {
+                    continue;
+                };
+                rules.extend(
+                    String::from_utf8_lossy(&output.stdout)
+                        .lines()
+                        .filter(|line| line.starts_with("-A "))
+                        .map(|line| format!("{} -t {table} {line}", binary(family))),
+                );
+            }
+        }
+        Ok(rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firewall::{PortMatch, Protocol};
+    use std::ops::RangeInclusive;
+
+    fn rule(hook: Hook, queues: RangeInclusive<u16>, ports: Vec<RangeInclusive<u16>>) -> Rule {
+        Rule {
+            family: Family::Ipv4,
+            hook,
+            queues,
+            uid: None,
+            ports: Some(PortMatch { protocol: Protocol::Tcp, ports }),
+            packets: Some(if hook.is_reply() { 1..=3 } else { 1..=6 }),
+            target: Target::Queue,
+        }
+    }
+
+    #[test]
+    fn matches_destination_ports_of_outgoing_packets() {
+        let rule = rule(Hook::Postrouting, 200..=201, vec![80..=80, 1000..=2000]);
+        assert_eq!(
+            rule_spec(&rule).join(" "),
+            "-p tcp -m multiport --dports 80,1000:2000 \
+             -m connbytes --connbytes-dir original --connbytes-mode packets --connbytes 1:6 \
+             -j NFQUEUE --queue-balance 200:201 --queue-bypass"
+        );
+    }
+
+    #[test]
+    fn matches_source_ports_of_replies() {
+        let rule = rule(Hook::Prerouting, 200..=200, vec![443..=443]);
+        assert_eq!(
+            rule_spec(&rule).join(" "),
+            "-p tcp -m multiport --sports 443 \
+             -m connbytes --connbytes-dir reply --connbytes-mode packets --connbytes 1:3 \
+             -j NFQUEUE --queue-num 200 --queue-bypass"
+        );
+    }
+
+    #[test]
+    fn returns_excluded_apps() {
+        let rule = Rule {
+            uid: Some(10123),
+            ports: None,
+            packets: None,
+            target: Target::Return,
+            ..rule(Hook::Postrouting, 200..=200, Vec::new())
+        };
+        assert_eq!(rule_spec(&rule).join(" "), "-m owner --uid-owner 10123 -j RETURN");
+    }
+}
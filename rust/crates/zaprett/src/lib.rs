@@ -1,22 +1,32 @@
+mod applist;
 pub mod cli;
 pub mod config;
 mod daemon;
+pub mod firewall;
 pub mod iptables_rust;
+mod lock;
+pub mod nftables_rust;
+mod rollback;
 mod service;
+mod status;
 mod autostart;
 mod path;
+mod resolver;
 mod strategy;
+mod sysctls;
 
-use crate::config::Manifest;
-use anyhow::{anyhow, Context};
+use crate::config::{Manifest, VersionedManifest};
+use crate::daemon::block_signals;
+use crate::resolver::Resolver;
+use anyhow::{bail, Context};
 use libnfqws::nfqws_main;
+use log::error;
 use libnfqws2::nfqws2_main;
+use sha2::{Digest, Sha256};
 use std::ffi::CString;
 use std::fs;
 use std::os::raw::c_char;
 use std::path::Path;
-use tokio::fs::File;
-use tokio::io::{copy, AsyncWriteExt};
 
 
 pub static DEFAULT_STRATEGY_NFQWS: &str = "
@@ -48,89 +58,127 @@ fn nfqws2_version() -> &'static str {
     env!("NFQWS2_VERSION")
 }
 
-pub async fn merge_files(
-    input_paths: &[impl AsRef<Path>],
-    output_path: impl AsRef<Path>,
-) -> anyhow::Result<()> {
-    let output_path = output_path.as_ref();
-    let mut output_file = File::create(output_path).await?;
-
-    for input in input_paths {
-        let input = input.as_ref();
-
-        let mut input_file = File::open(input)
-            .await
-            .map_err(|e| anyhow!("Failed to open {}: {e}", input.display()))?;
-
-        copy(&mut input_file, &mut output_file).await.map_err(|e| anyhow!(
-            format!(
-                "Failed to write contents of {}: {e}",
-                input.display()
-            )
-        ))?;
-    }
-
-    output_file.flush().await?;
-    Ok(())
+/// Builds the runtime the CLI runs on. Its threads block the signals the
+/// engine supervisor waits for, so in a foreground `supervise` only the
+/// supervisor's own thread takes them.
+pub fn runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .on_thread_start(|| {
+            if let Err(e) = block_signals() {
+                error!("Failed to block signals: {e}");
+            }
+        })
+        .build()
 }
 
 pub fn read_manifest(path: &Path) -> anyhow::Result<Manifest> {
     let content = fs::read_to_string(path).with_context(|| {
         format!("Failed to read manifest: {}", path.display())
     })?;
-    let manifest = serde_json::from_str(&content).with_context(|| {
+    let manifest = VersionedManifest::parse(&content).with_context(|| {
         format!("Failed to parse manifest: {}", path.display())
     })?;
+    let mut manifest = manifest.upgrade();
+    manifest.resolve_paths(path.parent().unwrap_or(Path::new("")));
     Ok(manifest)
 }
 
-pub fn check_dependencies(manifest: &Manifest) -> anyhow::Result<()> {
-    manifest.dependencies().iter().try_for_each(|dependency| {
-        let path = Path::new(dependency);
-        let manifest = read_manifest(&path).with_context(
-            || format!("Failed to check dependency: {}", dependency)
-        )?;
-        check_file(&manifest)
-    })
+/// Resolves every transitive dependency of `manifest` and checks their files exist.
+pub fn check_dependencies(manifest: &Manifest, resolver: &Resolver) -> anyhow::Result<()> {
+    resolver.check(manifest)
 }
 
+/// Checks that the file of `manifest` exists and matches its recorded size
+/// and sha256. Only a recorded sha256 needs the content to be read.
 pub fn check_file(manifest: &Manifest) -> anyhow::Result<()> {
-    if Path::new(manifest.file()).exists() {
-        Ok(())
-    } else {
-        Err(anyhow!("File not found: {}", manifest.file()))
+    if manifest.sha256().is_some() {
+        return read_file(manifest).map(|_| ());
+    }
+    let metadata = fs::metadata(manifest.file())
+        .with_context(|| format!("File not found: {}", manifest.file()))?;
+    check_size(manifest, metadata.len())
+}
+
+fn check_size(manifest: &Manifest, found: u64) -> anyhow::Result<()> {
+    if let Some(size) = *manifest.size()
+        && found != size
+    {
+        bail!(
+            "Size mismatch for {}: expected {size} bytes, found {found}",
+            manifest.file()
+        );
+    }
+    Ok(())
+}
+
+/// Reads the file of `manifest`, failing if it does not match the size or
+/// sha256 recorded in the manifest.
+pub fn read_file(manifest: &Manifest) -> anyhow::Result<Vec<u8>> {
+    let content = fs::read(manifest.file())
+        .with_context(|| format!("File not found: {}", manifest.file()))?;
+    check_size(manifest, content.len() as u64)?;
+    if let Some(expected) = manifest.sha256() {
+        let actual = sha256(&content);
+        if !actual.eq_ignore_ascii_case(expected) {
+            bail!(
+                "Checksum mismatch for {}: expected sha256 {expected}, found {actual}",
+                manifest.file()
+            );
+        }
     }
+    Ok(content)
+}
+
+pub fn sha256(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
 }
 
-pub fn get_manifest(path: &Path) -> anyhow::Result<Manifest> {
+pub fn get_manifest(path: &Path, resolver: &Resolver) -> anyhow::Result<Manifest> {
     let manifest = read_manifest(path)?;
     check_file(&manifest)?;
-    check_dependencies(&manifest)?;
+    check_dependencies(&manifest, resolver)?;
     Ok(manifest)
 }
 
-pub fn get_all_manifests(path: &Path) -> anyhow::Result<Vec<Manifest>> {
+/// Like [`get_manifest`], but also returns the verified content of its file.
+/// Use the returned bytes instead of reopening the file, which may have been
+/// replaced after the check.
+pub fn get_manifest_content(path: &Path, resolver: &Resolver) -> anyhow::Result<(Manifest, Vec<u8>)> {
+    let manifest = read_manifest(path)?;
+    check_dependencies(&manifest, resolver)?;
+    let content = read_file(&manifest)?;
+    Ok((manifest, content))
+}
+
+pub fn get_all_manifests(path: &Path, resolver: &Resolver) -> anyhow::Result<Vec<Manifest>> {
     path.read_dir()?.map(
         |manifest_path| {
             let manifest_path = manifest_path?;
-            get_manifest(&manifest_path.path()).with_context(|| {
+            get_manifest(&manifest_path.path(), resolver).with_context(|| {
                 format!("Failed to get manifest: {}", manifest_path.path().display())
             })
         }
     ).collect()
 }
-fn run_nfqws(args_str: &str) -> anyhow::Result<()> {
+/// Builds the full argv an engine worker on queue `qnum` is started with.
+pub fn engine_argv(engine: &str, strategy: &[String], qnum: u16) -> Vec<String> {
     let mut args = vec![
-        "nfqws".to_string(),
+        engine.to_string(),
         "--uid=0:0".to_string(),
-        "--qnum=200".to_string(),
+        format!("--qnum={qnum}"),
     ];
 
-    if args_str.trim().is_empty() {
+    if strategy.is_empty() {
         args.push("-v".to_string());
     } else {
-        args.extend(args_str.split_whitespace().map(String::from));
+        args.extend_from_slice(strategy);
     }
+    args
+}
+
+fn run_nfqws(strategy: &[String], qnum: u16) -> anyhow::Result<()> {
+    let args = engine_argv("nfqws", strategy, qnum);
 
     let c_args: Vec<CString> = args
         .into_iter()
@@ -152,18 +200,8 @@ fn run_nfqws(args_str: &str) -> anyhow::Result<()> {
 }
 
 
-fn run_nfqws2(args_str: &str) -> anyhow::Result<()> {
-    let mut args = vec![
-        "nfqws2".to_string(),
-        "--uid=0:0".to_string(),
-        "--qnum=200".to_string(),
-    ];
-
-    if args_str.trim().is_empty() {
-        args.push("-v".to_string());
-    } else {
-        args.extend(args_str.split_whitespace().map(String::from));
-    }
+fn run_nfqws2(strategy: &[String], qnum: u16) -> anyhow::Result<()> {
+    let args = engine_argv("nfqws2", strategy, qnum);
 
     let c_args: Vec<CString> = args
         .into_iter()
@@ -183,3 +221,73 @@ fn run_nfqws2(args_str: &str) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(file: &Path, sha256: Option<&str>, size: Option<u64>) -> Manifest {
+        serde_json::from_value(serde_json::json!({
+            "schema": 1, "id": "list", "name": "list", "version": "1.0.0", "author": "",
+            "description": "", "dependencies": [], "file": file,
+            "sha256": sha256, "size": size,
+        }))
+        .unwrap()
+    }
+
+    /// Reads a temp file holding `example.com\n`, naming it `list` in errors.
+    fn read(name: &str, sha256: Option<&str>, size: Option<u64>) -> Result<Vec<u8>, String> {
+        let file = std::env::temp_dir().join(format!("zaprett-{name}-{}", std::process::id()));
+        fs::write(&file, b"example.com\n").unwrap();
+        let content = read_file(&manifest(&file, sha256, size));
+        fs::remove_file(&file).unwrap();
+        content.map_err(|e| e.to_string().replace(&*file.to_string_lossy(), "list"))
+    }
+
+    #[test]
+    fn read_file_checks_the_size() {
+        assert_eq!(read("size-ok", None, Some(12)).unwrap(), b"example.com\n");
+        assert_eq!(
+            read("size", None, Some(3)).unwrap_err(),
+            "Size mismatch for list: expected 3 bytes, found 12"
+        );
+    }
+
+    #[test]
+    fn read_file_checks_the_checksum() {
+        let expected = sha256(b"example.org\n");
+        let actual = sha256(b"example.com\n");
+        assert_eq!(
+            read("sha256", Some(&expected), None).unwrap_err(),
+            format!("Checksum mismatch for list: expected sha256 {expected}, found {actual}")
+        );
+    }
+
+    #[test]
+    fn read_file_accepts_an_uppercase_checksum() {
+        let expected = sha256(b"example.com\n").to_uppercase();
+        assert_eq!(read("sha256-upper", Some(&expected), Some(12)).unwrap(), b"example.com\n");
+    }
+
+    #[test]
+    fn read_manifest_resolves_relative_paths() {
+        let dir = std::env::temp_dir().join(format!("zaprett-read-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.json");
+        let app = serde_json::json!({
+            "schema": 1, "id": "app", "name": "app", "version": "1.0.0", "author": "",
+            "description": "", "dependencies": ["../libs/lib.json", "base@^1"],
+            "file": "./app.txt",
+        });
+        fs::write(&path, app.to_string()).unwrap();
+        let manifest = read_manifest(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let manifest = manifest.unwrap();
+        assert_eq!(manifest.file(), &dir.join("./app.txt").to_string_lossy());
+        assert_eq!(
+            manifest.dependencies(),
+            &[dir.join("../libs/lib.json").to_string_lossy().into_owned(), "base@^1".to_string()]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use crate::config::ServiceType;
+use crate::path::path::MODULE_PATH;
+use anyhow::{Context, bail};
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Raw fd of the lock held by this process, so a forked daemon can drop its copy.
+static HELD: AtomicI32 = AtomicI32::new(-1);
+
+/// Serializes commands that change the service state. Lives outside `tmp`,
+/// which `start` wipes.
+pub struct ServiceLock(#[allow(dead_code)] Flock<File>);
+
+impl ServiceLock {
+    /// Blocks until no other zaprett command holds the lock.
+    pub fn acquire() -> anyhow::Result<Self> {
+        let path = MODULE_PATH.join("zaprett.lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let lock = Flock::lock(file, FlockArg::LockExclusive)
+            .map_err(|(_, e)| e)
+            .with_context(|| format!("Failed to lock {}", path.display()))?;
+        HELD.store(lock.as_raw_fd(), Ordering::SeqCst);
+        Ok(Self(lock))
+    }
+}
+
+impl Drop for ServiceLock {
+    fn drop(&mut self) {
+        HELD.store(-1, Ordering::SeqCst);
+    }
+}
+
+/// Closes the lock fd a daemon inherited from the command that started it,
+/// otherwise the lock would stay held for as long as the daemon runs.
+pub fn close_inherited_lock() {
+    let fd = HELD.swap(-1, Ordering::SeqCst);
+    if fd >= 0 {
+        let _ = nix::unistd::close(fd);
+    }
+}
+
+/// Identifies the running supervisor. The start time tells it apart from an
+/// unrelated process that got the same pid, e.g. after a reboot.
+#[derive(Serialize, Deserialize)]
+pub struct PidFile {
+    pub pid: i32,
+    pub start_time: u64,
+    pub engine: ServiceType,
+}
+
+impl PidFile {
+    pub fn current(engine: ServiceType) -> anyhow::Result<Self> {
+        let pid = std::process::id() as i32;
+        let Some((_, start_time)) = proc_stat(pid) else {
+            bail!("Failed to read /proc/{pid}/stat");
+        };
+        Ok(Self { pid, start_time, engine })
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Reads the pid file, returning `None` if it is missing or stale.
+    pub fn read(path: &Path) -> anyhow::Result<Option<Self>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let Ok(pid_file) = serde_json::from_str::<Self>(&contents) else {
+            return Ok(None);
+        };
+        Ok(pid_file.is_alive().then_some(pid_file))
+    }
+
+    pub fn pid(&self) -> Pid {
+        Pid::from_raw(self.pid)
+    }
+
+    fn is_alive(&self) -> bool {
+        proc_stat(self.pid)
+            .is_some_and(|(comm, start_time)| comm == "zaprett" && start_time == self.start_time)
+    }
+}
+
+/// Returns the command name and start time (in clock ticks since boot) of `pid`.
+fn proc_stat(pid: i32) -> Option<(String, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name may itself contain spaces and parentheses.
+    let (head, tail) = stat.rsplit_once(')')?;
+    let comm = head.split_once('(')?.1;
+    // Fields after the name start at field 3 (state); starttime is field 22.
+    let start_time = tail.split_whitespace().nth(19)?.parse().ok()?;
+    Some((comm.to_string(), start_time))
+}
@@ -0,0 +1,255 @@
+use crate::firewall::{Family, FirewallBackend, Hook, Rule, Target};
+use anyhow::{Context, bail};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const TABLE: &str = "zaprett";
+
+pub struct Nftables;
+
+fn chain(hook: Hook) -> &'static str {
+    match hook {
+        Hook::Postrouting => "type filter hook postrouting priority mangle; policy accept;",
+        Hook::Prerouting => "type filter hook prerouting priority mangle; policy accept;",
+        Hook::Forward => "type filter hook forward priority filter; policy accept;",
+    }
+}
+
+fn rule_spec(rule: &Rule) -> String {
+    let mut spec = format!("meta nfproto {}", rule.family.name());
+    if let Some(uid) = rule.uid {
+        spec.push_str(&format!(" meta skuid {uid}"));
+    }
+    if let Some(ports) = &rule.ports {
+        spec.push_str(&format!(" meta l4proto {}", ports.protocol.name()));
+        if !ports.ports.is_empty() {
+            let set: Vec<String> = ports
+                .ports
+                .iter()
+                .map(|range| match range.start() == range.end() {
+                    true => range.start().to_string(),
+                    false => format!("{}-{}", range.start(), range.end()),
+                })
+                .collect();
+            let direction = if rule.hook.is_reply() {
+                "sport"
+            } else {
+                "dport"
+            };
+            spec.push_str(&format!(" th {direction} {{ {} }}", set.join(", ")));
+        }
+    }
+    if let Some(packets) = &rule.packets {
+        let direction = if rule.hook.is_reply() {
+            "reply"
+        } else {
+            "original"
+        };
+        spec.push_str(&format!(
+            " ct {direction} packets {}-{}",
+            packets.start(),
+            packets.end()
+        ));
+    }
+    match rule.target {
+        Target::Queue => {
+            let (first, last) = (rule.queues.start(), rule.queues.end());
+            if first == last {
+                spec.push_str(&format!(" queue num {first} bypass"));
+            } else {
+                spec.push_str(&format!(" queue num {first}-{last} bypass,fanout"));
+            }
+        }
+        Target::Return => spec.push_str(" return"),
+    }
+    spec
+}
+
+fn ruleset(rules: &[Rule]) -> String {
+    // Declaring and deleting the table first makes the whole script replace any
+    // leftover table in a single transaction instead of failing on it.
+    let mut script =
+        format!("table inet {TABLE}\ndelete table inet {TABLE}\ntable inet {TABLE} {{\n");
+    for hook in [Hook::Postrouting, Hook::Prerouting, Hook::Forward] {
+        script.push_str(&format!(
+            "    chain {} {{\n        {}\n",
+            hook.name(),
+            chain(hook)
+        ));
+        for rule in rules.iter().filter(|rule| rule.hook == hook) {
+            script.push_str(&format!("        {}\n", rule_spec(rule)));
+        }
+        script.push_str("    }\n");
+    }
+    script.push_str("}\n");
+    script
+}
+
+pub fn nftables_available() -> bool {
+    Command::new("nft")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn table_exists() -> anyhow::Result<bool> {
+    let status = Command::new("nft")
+        .args(["list", "table", "inet", TABLE])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("Failed to run nft")?;
+    Ok(status.success())
+}
+
+impl FirewallBackend for Nftables {
+    fn install(&self, rules: &[Rule]) -> anyhow::Result<()> {
+        let mut child = Command::new("nft")
+            .arg("-f")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Failed to run nft")?;
+        child
+            .stdin
+            .take()
+            .context("Failed to open nft stdin")?
+            .write_all(ruleset(rules).as_bytes())?;
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("nft failed to create table {TABLE}: {status}");
+        }
+        Ok(())
+    }
+
+    // The inet table holds the rules of both families, so dropping it is enough.
+    // Without the table there is nothing to remove, so stopping twice is not an error.
+    fn remove(&self, _families: &[Family]) -> anyhow::Result<()> {
+        if !table_exists()? {
+            return Ok(());
+        }
+        let status = Command::new("nft")
+            .args(["delete", "table", "inet", TABLE])
+            .status()
+            .context("Failed to run nft")?;
+        if !status.success() {
+            bail!("nft failed to delete table {TABLE}: {status}");
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<String>> {
+        let output = Command::new("nft")
+            .args(["list", "table", "inet", TABLE])
+            .output()
+            .context("Failed to run nft")?;
+        if !output.status.success() {
+            return Ok(Vec::new());
+        }
+        Ok(rule_lines(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// The rules in `nft list table` output, without the table and chain declarations.
+fn rule_lines(table: &str) -> Vec<String> {
+    table
+        .lines()
+        .map(str::trim)
+        .filter(|line| {
+            !line.is_empty()
+                && *line != "}"
+                && !["table ", "chain ", "type "].iter().any(|prefix| line.starts_with(prefix))
+        })
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firewall::{PortMatch, Protocol};
+    use std::ops::RangeInclusive;
+
+    fn rule(hook: Hook, queues: RangeInclusive<u16>, ports: Vec<RangeInclusive<u16>>) -> Rule {
+        Rule {
+            family: Family::Ipv6,
+            hook,
+            queues,
+            uid: None,
+            ports: Some(PortMatch { protocol: Protocol::Udp, ports }),
+            packets: Some(if hook.is_reply() { 1..=3 } else { 1..=6 }),
+            target: Target::Queue,
+        }
+    }
+
+    #[test]
+    fn matches_destination_ports_of_outgoing_packets() {
+        let rule = rule(Hook::Postrouting, 200..=203, vec![443..=443, 50000..=50100]);
+        assert_eq!(
+            rule_spec(&rule),
+            "meta nfproto ipv6 meta l4proto udp th dport { 443, 50000-50100 } \
+             ct original packets 1-6 queue num 200-203 bypass,fanout"
+        );
+    }
+
+    #[test]
+    fn matches_source_ports_of_replies() {
+        let rule = rule(Hook::Prerouting, 200..=200, vec![443..=443]);
+        assert_eq!(
+            rule_spec(&rule),
+            "meta nfproto ipv6 meta l4proto udp th sport { 443 } \
+             ct reply packets 1-3 queue num 200 bypass"
+        );
+    }
+
+    #[test]
+    fn replaces_the_table_in_one_script() {
+        let queue = Rule {
+            ports: None,
+            packets: None,
+            ..rule(Hook::Postrouting, 200..=200, Vec::new())
+        };
+        let skip = Rule { uid: Some(10123), target: Target::Return, ..queue.clone() };
+        assert_eq!(
+            ruleset(&[skip, queue]),
+            "table inet zaprett\n\
+             delete table inet zaprett\n\
+             table inet zaprett {\n    \
+                 chain postrouting {\n        \
+                     type filter hook postrouting priority mangle; policy accept;\n        \
+                     meta nfproto ipv6 meta skuid 10123 return\n        \
+                     meta nfproto ipv6 queue num 200 bypass\n    \
+                 }\n    \
+                 chain prerouting {\n        \
+                     type filter hook prerouting priority mangle; policy accept;\n    \
+                 }\n    \
+                 chain forward {\n        \
+                     type filter hook forward priority filter; policy accept;\n    \
+                 }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn lists_queue_and_return_rules() {
+        let table = "table inet zaprett {
+            chain postrouting {
+                type filter hook postrouting priority mangle; policy accept;
+                meta nfproto ipv4 meta skuid 10123 return
+                meta nfproto ipv4 queue flags bypass to 200
+            }
+            chain prerouting {
+                type filter hook prerouting priority mangle; policy accept;
+            }
+        }";
+        assert_eq!(
+            rule_lines(table),
+            [
+                "meta nfproto ipv4 meta skuid 10123 return",
+                "meta nfproto ipv4 queue flags bypass to 200",
+            ]
+        );
+    }
+}
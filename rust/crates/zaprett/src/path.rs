@@ -6,6 +6,8 @@ pub mod path {
         LazyLock::new(|| Path::new("/data/adb/modules/zaprett"));
     pub static ZAPRETT_DIR_PATH: LazyLock<&Path> =
         LazyLock::new(|| Path::new("/storage/emulated/0/zaprett"));
+    pub static PACKAGES_LIST_PATH: LazyLock<&Path> =
+        LazyLock::new(|| Path::new("/data/system/packages.list"));
 }
 
 // Only for testing
@@ -18,4 +20,6 @@ pub mod path {
         LazyLock::new(|| Path::new("zaprett_module"));
     pub static ZAPRETT_DIR_PATH: LazyLock<&Path> =
         LazyLock::new(|| Path::new("zaprett_dir"));
+    pub static PACKAGES_LIST_PATH: LazyLock<&Path> =
+        LazyLock::new(|| Path::new("packages.list"));
 }
\ No newline at end of file
@@ -0,0 +1,356 @@
+use crate::config::Manifest;
+use crate::{check_file, read_manifest};
+use anyhow::{Context, bail};
+use log::warn;
+use semver::{Version, VersionReq};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Directories under the zaprett dir holding manifests, all sharing one id space.
+pub const MANIFEST_DIRS: &[&str] = &[
+    "manifests/lists/include",
+    "manifests/lists/exclude",
+    "manifests/ipset/include",
+    "manifests/ipset/exclude",
+    "manifests/libs",
+    "manifests/bin",
+    "manifests/strategies",
+];
+
+/// Every manifest found under a zaprett dir, indexed by id.
+pub struct Resolver {
+    manifests: HashMap<String, Indexed>,
+    duplicates: HashSet<String>,
+    /// Errors of the manifests that could not be read, by directory.
+    broken: Vec<(&'static str, String)>,
+}
+
+struct Indexed {
+    dir: &'static str,
+    path: PathBuf,
+    manifest: Manifest,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    InProgress,
+    Done,
+}
+
+/// State of one dependency walk.
+#[derive(Default)]
+struct Walk<'a> {
+    visits: HashMap<&'a str, Visit>,
+    order: Vec<&'a Manifest>,
+    requirements: HashMap<&'a str, Vec<(&'a str, VersionReq)>>,
+    errors: Vec<String>,
+}
+
+impl Resolver {
+    /// Indexes the manifests in [`MANIFEST_DIRS`] under `root`. Unreadable
+    /// manifests are skipped, so one broken file only affects what needs it.
+    pub fn load(root: &Path) -> Self {
+        let mut resolver = Resolver {
+            manifests: HashMap::new(),
+            duplicates: HashSet::new(),
+            broken: Vec::new(),
+        };
+        for dir in MANIFEST_DIRS {
+            let Ok(entries) = root.join(dir).read_dir() else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                match read_manifest(&path) {
+                    Ok(manifest) => resolver.add(dir, path, manifest),
+                    Err(e) => {
+                        warn!("Skipping manifest: {e:#}");
+                        resolver.broken.push((dir, format!("{e:#}")));
+                    }
+                }
+            }
+        }
+        resolver
+    }
+
+    fn add(&mut self, dir: &'static str, path: PathBuf, manifest: Manifest) {
+        match self.manifests.entry(manifest.id().clone()) {
+            Entry::Occupied(entry) => {
+                self.duplicates.insert(entry.key().clone());
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Indexed { dir, path, manifest });
+            }
+        }
+    }
+
+    /// Finds manifest `id` among the ones in `dir` and checks its dependencies.
+    /// When it is missing, the manifests of `dir` that could not be read are
+    /// listed as they may be the one meant.
+    pub fn lookup(&self, dir: &str, id: &str) -> anyhow::Result<&Manifest> {
+        if self.duplicates.contains(id) {
+            bail!("{id} is defined by several manifests");
+        }
+        let Some(Indexed { manifest, .. }) =
+            self.manifests.get(id).filter(|indexed| indexed.dir == dir)
+        else {
+            let mut error = format!("Manifest not found: {id}");
+            for (_, broken) in self.broken.iter().filter(|(broken_dir, _)| *broken_dir == dir) {
+                error.push_str(&format!("\nskipped unreadable manifest: {broken}"));
+            }
+            bail!(error);
+        };
+        self.check(manifest)?;
+        Ok(manifest)
+    }
+
+    /// The manifests found in `dir`, ordered by id.
+    pub fn manifests_in(&self, dir: &str) -> Vec<&Manifest> {
+        let mut manifests: Vec<&Manifest> = self
+            .manifests
+            .values()
+            .filter(|indexed| indexed.dir == dir)
+            .map(|indexed| &indexed.manifest)
+            .collect();
+        manifests.sort_by(|a, b| a.id().cmp(b.id()));
+        manifests
+    }
+
+    /// Resolves the dependencies of `manifest` and checks that their files exist.
+    pub fn check(&self, manifest: &Manifest) -> anyhow::Result<()> {
+        let order = self
+            .resolve(manifest)
+            .with_context(|| format!("Failed to resolve dependencies of {}", manifest.id()))?;
+        // The manifest itself comes last; its own file is checked where it is used.
+        order[..order.len() - 1].iter().try_for_each(|dependency| check_file(dependency))
+    }
+
+    /// Walks the dependency graph of `root` and returns every manifest it
+    /// needs, dependencies before their dependents and `root` last.
+    pub fn resolve<'a>(&'a self, root: &'a Manifest) -> anyhow::Result<Vec<&'a Manifest>> {
+        let mut walk = Walk::default();
+        self.visit(root, &mut vec![root.id().as_str()], &mut walk);
+        let mut required: Vec<_> = walk.requirements.iter().collect();
+        required.sort_by_key(|(id, _)| **id);
+        for (id, requirements) in required {
+            if let Err(e) = self.check_version(id, requirements) {
+                walk.errors.push(e.to_string());
+            }
+        }
+        if !walk.errors.is_empty() {
+            bail!(walk.errors.join("\n"));
+        }
+        Ok(walk.order)
+    }
+
+    /// Checks the version of `id` against what every dependent asks for.
+    fn check_version(&self, id: &str, requirements: &[(&str, VersionReq)]) -> anyhow::Result<()> {
+        let manifest = &self.manifests[id].manifest;
+        let Ok(version) = Version::parse(manifest.version()) else {
+            bail!(
+                "{id}: version {} is not a semantic version",
+                manifest.version()
+            );
+        };
+        if requirements
+            .iter()
+            .all(|(_, requirement)| requirement.matches(&version))
+        {
+            return Ok(());
+        }
+        let wanted: Vec<String> = requirements
+            .iter()
+            .map(|(dependent, requirement)| format!("{dependent} needs {requirement}"))
+            .collect();
+        bail!("Version conflict on {id} {version}: {}", wanted.join(", "))
+    }
+
+    fn visit<'a>(&'a self, manifest: &'a Manifest, path: &mut Vec<&'a str>, walk: &mut Walk<'a>) {
+        walk.visits.insert(manifest.id(), Visit::InProgress);
+        for dependency in manifest.dependencies() {
+            let (id, requirement) = match self.parse_dependency(dependency) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    walk.errors.push(format!("{}: {e}", manifest.id()));
+                    continue;
+                }
+            };
+            if self.duplicates.contains(id) {
+                walk.errors.push(format!(
+                    "{}: dependency {id} is defined by several manifests",
+                    manifest.id()
+                ));
+                continue;
+            }
+            let Some(Indexed { manifest: dependency, .. }) = self.manifests.get(id) else {
+                walk.errors
+                    .push(format!("{}: missing dependency {id}", manifest.id()));
+                continue;
+            };
+            if let Some(requirement) = requirement {
+                walk.requirements
+                    .entry(dependency.id())
+                    .or_default()
+                    .push((manifest.id(), requirement));
+            }
+            match walk.visits.get(id) {
+                Some(Visit::Done) => continue,
+                Some(Visit::InProgress) => {
+                    let start = path.iter().position(|visited| *visited == id).unwrap_or(0);
+                    walk.errors.push(format!(
+                        "Dependency cycle: {} -> {id}",
+                        path[start..].join(" -> ")
+                    ));
+                    continue;
+                }
+                None => {}
+            }
+            path.push(dependency.id());
+            self.visit(dependency, path, walk);
+            path.pop();
+        }
+        walk.visits.insert(manifest.id(), Visit::Done);
+        walk.order.push(manifest);
+    }
+
+    /// Dependencies are manifest ids, optionally with a version requirement
+    /// as in `id@^1.2`. Older manifests list paths to other manifests instead,
+    /// which are mapped to the id found there. That id must be indexed from the
+    /// same file, or the path would silently stand for another manifest.
+    fn parse_dependency<'a>(
+        &'a self,
+        dependency: &'a str,
+    ) -> anyhow::Result<(&'a str, Option<VersionReq>)> {
+        if !dependency.contains('/') {
+            return match dependency.split_once('@') {
+                Some((id, requirement)) => match VersionReq::parse(requirement) {
+                    Ok(requirement) => Ok((id, Some(requirement))),
+                    Err(e) => bail!("invalid version requirement in {dependency}: {e}"),
+                },
+                None => Ok((dependency, None)),
+            };
+        }
+        let path = Path::new(dependency);
+        let id = read_manifest(path)?.id().clone();
+        match self.manifests.get_key_value(&id) {
+            Some((id, indexed)) if same_file(path, &indexed.path) => Ok((id, None)),
+            Some((id, indexed)) => bail!(
+                "{dependency} has id {id}, which is defined by {}",
+                indexed.path.display()
+            ),
+            None => bail!("{dependency} is not in a manifest directory"),
+        }
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temporary zaprett dir, removed when dropped.
+    struct Root(PathBuf);
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn manifests(name: &str, manifests: &[(&str, &str, &[&str])]) -> Root {
+        let root =
+            std::env::temp_dir().join(format!("zaprett-resolver-{name}-{}", std::process::id()));
+        let dir = root.join("manifests/lists/include");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (id, version, dependencies) in manifests {
+            let manifest = serde_json::json!({
+                "schema": 1, "id": id, "name": id, "version": version, "author": "",
+                "description": "", "dependencies": dependencies, "file": "",
+            });
+            std::fs::write(dir.join(format!("{id}.json")), manifest.to_string()).unwrap();
+        }
+        Root(root)
+    }
+
+    fn resolve(root: &Root, id: &str) -> anyhow::Result<Vec<String>> {
+        let resolver = Resolver::load(&root.0);
+        let manifest = &resolver.manifests[id].manifest;
+        Ok(resolver
+            .resolve(manifest)?
+            .iter()
+            .map(|m| m.id().clone())
+            .collect())
+    }
+
+    #[test]
+    fn orders_transitive_dependencies_first() {
+        let root = manifests(
+            "order",
+            &[
+                ("app", "1.0.0", &["lib", "base@^1.2"]),
+                ("lib", "1.0.0", &["base"]),
+                ("base", "1.4.0", &[]),
+            ],
+        );
+        assert_eq!(resolve(&root, "app").unwrap(), ["base", "lib", "app"]);
+    }
+
+    #[test]
+    fn reports_cycles_and_missing_dependencies() {
+        let root = manifests(
+            "broken",
+            &[
+                ("a", "1.0.0", &["b", "gone"]),
+                ("b", "1.0.0", &["c"]),
+                ("c", "1.0.0", &["b"]),
+            ],
+        );
+        assert_eq!(
+            resolve(&root, "a").unwrap_err().to_string(),
+            "Dependency cycle: b -> c -> b\na: missing dependency gone"
+        );
+    }
+
+    #[test]
+    fn reports_version_conflicts() {
+        let root = manifests(
+            "conflict",
+            &[
+                ("app", "1.0.0", &["lib@^1", "base@^1.2"]),
+                ("lib", "1.3.0", &["base@^2"]),
+                ("base", "1.4.0", &[]),
+            ],
+        );
+        assert_eq!(
+            resolve(&root, "app").unwrap_err().to_string(),
+            "Version conflict on base 1.4.0: lib needs ^2, app needs ^1.2"
+        );
+    }
+
+    #[test]
+    fn rejects_path_dependencies_naming_another_file() {
+        let root = manifests("path", &[("base", "1.0.0", &[])]);
+        let copy = root.0.join("copy.json");
+        std::fs::copy(root.0.join("manifests/lists/include/base.json"), &copy).unwrap();
+        let app = serde_json::json!({
+            "schema": 1, "id": "app", "name": "app", "version": "1.0.0", "author": "",
+            "description": "", "dependencies": [copy], "file": "",
+        });
+        std::fs::write(root.0.join("manifests/lists/include/app.json"), app.to_string()).unwrap();
+        assert_eq!(
+            resolve(&root, "app").unwrap_err().to_string(),
+            format!(
+                "app: {} has id base, which is defined by {}",
+                copy.display(),
+                root.0.join("manifests/lists/include/base.json").display()
+            )
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use crate::firewall::{Family, FirewallBackend};
+use crate::lock::PidFile;
+use crate::sysctls::restore_sysctls;
+use log::{error, info};
+use nix::errno::Errno;
+use nix::sys::signal::{Signal, killpg};
+use std::fs;
+use std::path::PathBuf;
+
+enum Step {
+    TmpDir(PathBuf),
+    Sysctls(PathBuf),
+    Firewall {
+        backend: Box<dyn FirewallBackend>,
+        families: &'static [Family],
+    },
+    Engine {
+        pid_file: PathBuf,
+    },
+}
+
+/// Side effects of a service start, undone in reverse order if a later step fails.
+#[derive(Default)]
+pub struct Rollback {
+    steps: Vec<Step>,
+}
+
+impl Step {
+    fn undo(self) -> anyhow::Result<()> {
+        match self {
+            // Engine logs stay so the reason of the failed start can still be read.
+            Step::TmpDir(path) => {
+                for entry in fs::read_dir(path)? {
+                    let path = entry?.path();
+                    if path.extension().is_some_and(|ext| ext == "out" || ext == "err") {
+                        continue;
+                    }
+                    if path.is_dir() {
+                        fs::remove_dir_all(&path)?;
+                    } else {
+                        fs::remove_file(&path)?;
+                    }
+                }
+            }
+            Step::Sysctls(snapshot) => restore_sysctls(&snapshot)?,
+            Step::Firewall { backend, families } => backend.remove(families)?,
+            Step::Engine { pid_file } => {
+                if let Some(pid) = PidFile::read(&pid_file)? {
+                    match killpg(pid.pid(), Signal::SIGKILL) {
+                        Ok(()) | Err(Errno::ESRCH) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                    fs::remove_file(&pid_file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rollback {
+    pub fn tmp_dir(&mut self, path: PathBuf) {
+        self.steps.push(Step::TmpDir(path));
+    }
+
+    pub fn sysctls(&mut self, snapshot: PathBuf) {
+        self.steps.push(Step::Sysctls(snapshot));
+    }
+
+    pub fn firewall(&mut self, backend: Box<dyn FirewallBackend>, families: &'static [Family]) {
+        self.steps.push(Step::Firewall { backend, families });
+    }
+
+    pub fn engine(&mut self, pid_file: PathBuf) {
+        self.steps.push(Step::Engine { pid_file });
+    }
+
+    pub fn run(self) {
+        info!("Rolling back partial start");
+        for step in self.steps.into_iter().rev() {
+            if let Err(e) = step.undo() {
+                error!("Rollback step failed: {e:#}");
+            }
+        }
+    }
+}
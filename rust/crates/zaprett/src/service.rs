@@ -1,42 +1,30 @@
-use crate::config::{Config, Manifest, ServiceType};
-use crate::daemon::daemonize_nfqws;
-use crate::daemon::daemonize_nfqws2;
-use crate::iptables_rust::{clear_iptables_rules, setup_iptables_rules};
-use crate::{get_manifest, get_all_manifests, DEFAULT_STRATEGY_NFQWS, DEFAULT_STRATEGY_NFQWS2};
-use anyhow::bail;
-use log::info;
-use nix::sys::signal::{Signal, kill};
+use crate::applist::app_filter;
+use crate::config::{Config, Instance, ServiceType};
+use crate::daemon::{daemonize_engine, pid_path, restart_count, supervise, EngineSpec, STARTUP_GRACE};
+use crate::lock::{PidFile, ServiceLock};
+use crate::firewall::{instance_rules, FirewallBackend, FirewallState, Rule};
+use crate::resolver::Resolver;
+use crate::rollback::Rollback;
+use crate::status::{log_tail, EngineState, InstanceStatus, Status};
+use crate::sysctls::{restore_sysctls, save_sysctls, set_sysctls};
+use crate::{engine_argv, get_manifest_content, DEFAULT_STRATEGY_NFQWS, DEFAULT_STRATEGY_NFQWS2};
+use anyhow::{anyhow, bail, Context};
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::sys::signal::{Signal, kill, killpg};
 use nix::unistd::{Pid, Uid};
-use regex::Regex;
 use std::borrow::Cow;
-use std::collections::{HashMap};
 use std::io::ErrorKind;
-use std::path::Path;
-use sysctl::{Ctl, CtlValue, Sysctl};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use sysinfo::{Pid as SysPid, System};
 use tokio::fs;
 use tokio::io::AsyncReadExt;
-use crate::path::path::{MODULE_PATH, ZAPRETT_DIR_PATH};
-use crate::strategy::prepare_manifests;
-
-pub async fn start_service() -> anyhow::Result<()> {
-    if !Uid::effective().is_root() {
-        bail!("Running not from root, exiting");
-    };
-
-    if service_status().await? {
-        bail!("zaprett already started")
-    }
-
-    println!("Starting zaprett service...");
-
-    let tmp_dir = MODULE_PATH.join("tmp");
-    if tmp_dir.exists() {
-        fs::remove_dir_all(&tmp_dir).await?;
-    }
-
-    fs::create_dir_all(&tmp_dir).await?;
+use tokio::time::sleep;
+use crate::path::path::{MODULE_PATH, PACKAGES_LIST_PATH, ZAPRETT_DIR_PATH};
+use crate::strategy::{resolve_includes, tokenize, Strategy, Variables};
 
+pub(crate) async fn read_config() -> anyhow::Result<Config> {
     let config_path = ZAPRETT_DIR_PATH.join("config.json");
     let mut config_contents = String::new();
 
@@ -56,113 +44,445 @@ pub async fn start_service() -> anyhow::Result<()> {
         Err(e) => return Err(e.into()),
     }
 
-    let config: Config = serde_json::from_str(&config_contents)?;
-    let strategy_path = match config.service_type() {
-        ServiceType::Nfqws => config.strategy(),
-        ServiceType::Nfqws2 => config.strategy_nfqws2(),
-    };
-    let default_strategy = match config.service_type() {
+    Ok(serde_json::from_str(&config_contents)?)
+}
+
+/// Installs the rules of every instance, each scoped to its own rendered strategy.
+/// Rules of earlier instances match first, so an unfiltered instance shadows later ones.
+fn setup_firewall(
+    config: &Config,
+    engines: &[(Instance, Strategy)],
+    packages_list: &Path,
+    firewall: &dyn FirewallBackend,
+) -> anyhow::Result<()> {
+    let apps = app_filter(config, packages_list)?;
+    let rules: Vec<Rule> = engines
+        .iter()
+        .flat_map(|(instance, strategy)| instance_rules(config, instance, strategy, &apps))
+        .collect();
+    firewall.install(&rules)
+}
+
+fn firewall_state_path() -> PathBuf {
+    MODULE_PATH.join("tmp/firewall.json")
+}
+
+/// The firewall the running service installed its rules with, falling back to
+/// the config for services started before this was recorded.
+fn installed_firewall(config: &Config) -> FirewallState {
+    FirewallState::load(&firewall_state_path()).unwrap_or_else(|| FirewallState::new(config))
+}
+
+/// Loads the strategy of `instance`, splits it into arguments and substitutes
+/// their variables, copying the referenced manifest files and merged lists into `tmp_dir`.
+/// Without `write` the arguments point into `tmp_dir` but nothing is copied there.
+async fn render_strategy(
+    config: &Config,
+    instance: &Instance,
+    resolver: &Resolver,
+    tmp_dir: &Path,
+    write: bool,
+) -> anyhow::Result<Strategy> {
+    let strategy_path = instance.strategy();
+    let default_strategy = match instance.service_type() {
         ServiceType::Nfqws => DEFAULT_STRATEGY_NFQWS,
         ServiceType::Nfqws2 => DEFAULT_STRATEGY_NFQWS2
     };
+    let mut stack = Vec::new();
     let start = if strategy_path.is_empty() || !Path::new(strategy_path).exists() {
         Cow::Borrowed(default_strategy)
     } else {
-        let manifest = get_manifest(Path::new(strategy_path))?;
-        Cow::Owned(fs::read_to_string(manifest.file()).await?)
+        let (manifest, content) = get_manifest_content(Path::new(strategy_path), resolver)?;
+        stack.push(manifest.id().clone());
+        Cow::Owned(String::from_utf8(content)
+            .with_context(|| format!("Failed to read {}", manifest.file()))?)
     };
-    let regex_hostlists = Regex::new(r"\$\{hostlists\}")?;
-    let regex_hostlist = Regex::new(r"\$\{hostlist:([^}]+)\}")?;
-    let regex_hostlist_exclude = Regex::new(r"\$\{hostlist_exclude:([^}]+)\}")?;
-    let regex_ipset = Regex::new(r"\$\{ipset:([^}]+)\}")?;
-    let regex_ipset_exclude = Regex::new(r"\$\{ipset_exclude:([^}]+)\}")?;
-    let regex_ipsets = Regex::new(r"\$\{ipsets\}")?;
-    let regex_libsdir = Regex::new(r"\$\{lua_lib:([^}]+)\}")?;
-    let regex_bindir = Regex::new(r"\$\{bin:([^}]+)\}")?;
-    let (hosts, ipsets) = config.list_type().merge(&config).await?;
-    let hostlists: HashMap<String, Manifest> =
-        get_all_manifests(&ZAPRETT_DIR_PATH.join("manifests/lists/include"))
-            .unwrap_or_default()
-            .into_iter()
-            .map(|m| (m.id().clone(), m))
-            .collect();
-    let hostlists_exclude: HashMap<String, Manifest> =
-        get_all_manifests(&ZAPRETT_DIR_PATH.join("manifests/lists/exclude"))
-            .unwrap_or_default()
-            .into_iter()
-            .map(|m| (m.id().clone(), m))
-            .collect();
-    let ipset: HashMap<String, Manifest> =
-        get_all_manifests(&ZAPRETT_DIR_PATH.join("manifests/ipset/include"))
-            .unwrap_or_default()
-            .into_iter()
-            .map(|m| (m.id().clone(), m))
-            .collect();
-    let ipset_exclude: HashMap<String, Manifest> =
-        get_all_manifests(&ZAPRETT_DIR_PATH.join("manifests/ipset/exclude"))
-            .unwrap_or_default()
-            .into_iter()
-            .map(|m| (m.id().clone(), m))
-            .collect();
-    let lua_lib: HashMap<String, Manifest> =
-        get_all_manifests(&ZAPRETT_DIR_PATH.join("manifests/libs"))
-            .unwrap_or_default()
-            .into_iter()
-            .map(|m| (m.id().clone(), m))
-            .collect();
-    let bins: HashMap<String, Manifest> =
-        get_all_manifests(&ZAPRETT_DIR_PATH.join("manifests/bin"))
-            .unwrap_or_default()
+    let tokens = tokenize(&start).context("Failed to parse strategy")?;
+    let tokens = resolve_includes(tokens, resolver, &mut stack)?;
+    let (hosts, ipsets) = config.list_type().merge(config, resolver, tmp_dir, write).await?;
+    let mut variables = Variables::new(tmp_dir, resolver, write);
+    variables
+        .set("zaprettdir", ZAPRETT_DIR_PATH.to_string_lossy())
+        .set("moduledir", MODULE_PATH.to_string_lossy())
+        .set("tmpdir", tmp_dir.to_string_lossy())
+        .set("queue", instance.queue().num().to_string())
+        .set("hostlists", hosts)
+        .set("ipsets", ipsets)
+        .namespace("hostlist", "manifests/lists/include")
+        .namespace("hostlist_exclude", "manifests/lists/exclude")
+        .namespace("ipset", "manifests/ipset/include")
+        .namespace("ipset_exclude", "manifests/ipset/exclude")
+        .namespace("lua_lib", "manifests/libs")
+        .namespace("bin", "manifests/bin")
+        .directory("libsdir", "lua_lib");
+    let args = variables.expand(&tokens).context("Failed to render strategy")?;
+    Strategy::parse(*instance.service_type(), args).context("Invalid strategy")
+}
+
+/// Prints the strategy warnings to stderr, where they are seen even though
+/// the log only shows errors by default.
+fn print_warnings(instance: &Instance, strategy: &Strategy) {
+    for warning in &strategy.warnings {
+        eprintln!("warning: {}: {warning}", instance.name());
+    }
+}
+
+/// Runs the substitution pipeline for an instance and returns the argv of its
+/// first worker, with the `tmp` paths the engine would get, and the strategy
+/// warnings. Nothing is written, so this needs neither root nor a stopped service.
+pub async fn render_argv(
+    name: Option<&str>,
+    engine: Option<ServiceType>,
+    strategy: Option<&str>,
+) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let config = read_config().await?;
+    let instances = config.instances()?;
+    let mut instance = match (name, engine) {
+        (Some(name), _) => find_instance(instances, name)?,
+        (None, Some(engine)) => instances
             .into_iter()
-            .map(|m| (m.id().clone(), m))
-            .collect();
-    let strat_modified = prepare_manifests(&start, &regex_hostlist, &hostlists, &tmp_dir)?;
-    let strat_modified = prepare_manifests(&strat_modified, &regex_hostlist_exclude, &hostlists_exclude, &tmp_dir)?;
-    let strat_modified = prepare_manifests(&strat_modified, &regex_ipset, &ipset, &tmp_dir)?;
-    let strat_modified = prepare_manifests(&strat_modified, &regex_ipset_exclude, &ipset_exclude, &tmp_dir)?;
-    let strat_modified = prepare_manifests(&strat_modified, &regex_libsdir, &lua_lib, &tmp_dir)?;
-    let strat_modified = prepare_manifests(&strat_modified, &regex_bindir, &bins, &tmp_dir)?;
-    let strat_modified = regex_hostlists.replace_all(&strat_modified, &hosts);
-    let strat_modified = regex_ipsets.replace_all(&strat_modified, &ipsets);
-    let strat_modified = strat_modified.into_owned();
+            .find(|instance| *instance.service_type() == engine)
+            .ok_or_else(|| anyhow!("No instance runs {}, pick one with --instance", engine.name()))?,
+        (None, None) => instances.into_iter().next().context("No instances configured")?,
+    };
+    if let Some(engine) = engine
+        && *instance.service_type() != engine
+    {
+        bail!(
+            "Instance {} runs {}, not {}",
+            instance.name(),
+            instance.service_type().name(),
+            engine.name()
+        );
+    }
+    if let Some(strategy) = strategy {
+        // Only a configured strategy falls back to the default when missing.
+        if !Path::new(strategy).exists() {
+            bail!("Strategy manifest not found: {strategy}");
+        }
+        instance.set_strategy(strategy.to_string());
+    }
+
+    let resolver = Resolver::load(*ZAPRETT_DIR_PATH);
+    let rendered = render_strategy(&config, &instance, &resolver, &MODULE_PATH.join("tmp"), false).await?;
+    let argv = engine_argv(instance.service_type().name(), &rendered.args, instance.queue().num());
+    Ok((argv, rendered.warnings))
+}
 
-    let ctl = Ctl::new("net.netfilter.nf_conntrack_tcp_be_liberal")?;
-    ctl.set_value(CtlValue::String("1".into()))?;
+pub async fn start_service() -> anyhow::Result<()> {
+    if !Uid::effective().is_root() {
+        bail!("Running not from root, exiting");
+    };
 
-    setup_iptables_rules()?;
+    let _lock = ServiceLock::acquire()?;
+    start_locked().await
+}
 
-    if config.service_type() == &ServiceType::Nfqws {
-        daemonize_nfqws(&strat_modified).await;
+async fn start_locked() -> anyhow::Result<()> {
+    if service_status().await? {
+        bail!("zaprett already started")
+    }
+
+    println!("Starting zaprett service...");
+
+    let mut rollback = Rollback::default();
+    match start_steps(&mut rollback).await {
+        Ok(()) => {
+            println!("zaprett service started!");
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to start zaprett service: {e:#}");
+            rollback.run();
+            Err(e)
+        }
+    }
+}
+
+async fn start_steps(rollback: &mut Rollback) -> anyhow::Result<()> {
+    let tmp_dir = MODULE_PATH.join("tmp");
+    let sysctl_snapshot = tmp_dir.join("sysctl.json");
+    if tmp_dir.exists() {
+        // A snapshot left by a run that was never stopped holds the real
+        // original values; restore them before taking a new one.
+        restore_sysctls(&sysctl_snapshot)?;
+        fs::remove_dir_all(&tmp_dir).await?;
+    }
+
+    fs::create_dir_all(&tmp_dir).await?;
+    rollback.tmp_dir(tmp_dir.clone());
+
+    let config = read_config().await?;
+    let resolver = Resolver::load(*ZAPRETT_DIR_PATH);
+    let mut engines = Vec::new();
+    for instance in config.instances()? {
+        let strategy = render_strategy(&config, &instance, &resolver, &tmp_dir, true).await?;
+        print_warnings(&instance, &strategy);
+        EngineState::new(&config, &instance, &resolver).save(&state_path(instance.name()))?;
+        engines.push((instance, strategy));
     }
-    else if config.service_type() == &ServiceType::Nfqws2 {
-        daemonize_nfqws2(&strat_modified).await;
+
+    save_sysctls(&sysctl_snapshot)?;
+    rollback.sysctls(sysctl_snapshot);
+    set_sysctls()?;
+
+    let firewall_state = FirewallState::new(&config);
+    let firewall = firewall_state.backend();
+    setup_firewall(&config, &engines, &PACKAGES_LIST_PATH, &*firewall)?;
+    rollback.firewall(firewall, firewall_state.ip_family.families());
+    firewall_state.save(&firewall_state_path())?;
+
+    let apps = app_filter(&config, &PACKAGES_LIST_PATH)?;
+    let mut names = Vec::new();
+    for (instance, strategy) in engines {
+        let name = instance.name().clone();
+        rollback.engine(pid_path(&name));
+        let spec = EngineSpec::new(&config, &instance, strategy, &apps);
+        let reload_name = name.clone();
+        daemonize_engine(spec, move |write| reload_engine(&reload_name, write)).await?;
+        names.push(name);
     }
-    else {
-        bail!("Broken config file!");
+
+    for name in names {
+        wait_started(&name).await?;
     }
+    Ok(())
+}
+
+fn state_path(name: &str) -> PathBuf {
+    MODULE_PATH.join(format!("tmp/{name}.state.json"))
+}
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Waits for the daemon to write its pid file and checks the engine survives
+/// its first moments, which is when bad strategy arguments make it exit.
+async fn wait_started(name: &str) -> anyhow::Result<()> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    let pid = loop {
+        if let Some(pid_file) = PidFile::read(&pid_path(name))? {
+            break pid_file.pid();
+        }
+        if Instant::now() >= deadline {
+            bail!(
+                "Engine {name} did not start within {}s{}",
+                STARTUP_TIMEOUT.as_secs(),
+                engine_log(name)
+            );
+        }
+        sleep(Duration::from_millis(50)).await;
+    };
 
-    println!("zaprett service started!");
+    // The supervisor gives up on workers failing within STARTUP_GRACE, give it time to notice.
+    sleep(STARTUP_GRACE * 2).await;
+    if !engine_alive(pid) {
+        bail!("Engine {name} exited right after start{}", engine_log(name));
+    }
     Ok(())
 }
 
+/// The end of the stderr log of `name`, for errors about an engine that failed to start.
+fn engine_log(name: &str) -> String {
+    let path = MODULE_PATH.join(format!("tmp/{name}.err"));
+    let tail = log_tail(&path);
+    if tail.is_empty() {
+        return format!(", {} is empty", path.display());
+    }
+    format!(", last lines of {}:\n{}", path.display(), tail.join("\n"))
+}
+
 pub async fn stop_service() -> anyhow::Result<()> {
     if !Uid::effective().is_root() {
         bail!("Running not from root, exiting");
     };
 
-    if !service_status().await? {
-        info!("zaprett service already stopped");
-        return Ok(())
+    let _lock = ServiceLock::acquire()?;
+    stop_locked().await
+}
+
+async fn stop_locked() -> anyhow::Result<()> {
+    let config = read_config().await?;
+
+    let timeout = Duration::from_secs(config.stop_timeout().unwrap_or(DEFAULT_STOP_TIMEOUT));
+    let mut shutdown = Shutdown::NotRunning;
+    for (name, pid_file) in running_instances()? {
+        match terminate(pid_file.pid(), timeout).await? {
+            Shutdown::NotRunning => {}
+            Shutdown::Terminated => shutdown = shutdown.max(Shutdown::Terminated),
+            Shutdown::Killed => {
+                warn!("Engine {name} had to be killed");
+                shutdown = Shutdown::Killed;
+            }
+        }
+    }
+
+    // Rules go only once the engine is gone: with --queue-bypass traffic then
+    // flows untouched instead of hitting a queue nobody reads.
+    let firewall = installed_firewall(&config);
+    firewall.backend().remove(firewall.ip_family.families())?;
+    if let Err(e) = std::fs::remove_file(firewall_state_path())
+        && e.kind() != ErrorKind::NotFound
+    {
+        warn!("Failed to remove {}: {e}", firewall_state_path().display());
+    }
+    restore_sysctls(&MODULE_PATH.join("tmp/sysctl.json"))?;
+
+    match shutdown {
+        Shutdown::NotRunning => info!("zaprett service already stopped"),
+        Shutdown::Terminated => println!("zaprett service stopped"),
+        Shutdown::Killed => println!("zaprett service stopped (killed after timeout)"),
+    }
+    Ok(())
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Shutdown {
+    NotRunning,
+    Terminated,
+    Killed,
+}
+
+const DEFAULT_STOP_TIMEOUT: u64 = 5;
+
+/// Signals the process group an engine supervisor leads. A foreground
+/// `supervise` stays in the terminal's group, so it is signalled alone and
+/// stops its workers itself.
+fn signal_engine(pid: Pid, signal: impl Into<Option<Signal>> + Copy) -> nix::Result<()> {
+    match killpg(pid, signal) {
+        Err(Errno::ESRCH) => kill(pid, signal),
+        result => result,
+    }
+}
+
+fn engine_alive(pid: Pid) -> bool {
+    signal_engine(pid, None).is_ok()
+}
+
+async fn wait_exit(pid: Pid, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while engine_alive(pid) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
+
+/// Stops the engine process group with SIGTERM, escalating to SIGKILL after `timeout`.
+async fn terminate(pid: Pid, timeout: Duration) -> anyhow::Result<Shutdown> {
+    match signal_engine(pid, Signal::SIGTERM) {
+        Ok(()) => {}
+        Err(Errno::ESRCH) => return Ok(Shutdown::NotRunning),
+        Err(e) => return Err(e.into()),
+    }
+    if wait_exit(pid, timeout).await {
+        return Ok(Shutdown::Terminated);
+    }
+
+    warn!("Engine did not exit within {}s, sending SIGKILL", timeout.as_secs());
+    match signal_engine(pid, Signal::SIGKILL) {
+        Ok(()) | Err(Errno::ESRCH) => {}
+        Err(e) => return Err(e.into()),
+    }
+    if !wait_exit(pid, Duration::from_secs(1)).await {
+        bail!("Engine process group {pid} is still alive after SIGKILL");
+    }
+    Ok(Shutdown::Killed)
+}
+
+/// Runs the supervisor of one engine instance in the foreground, leaving
+/// firewall rules and sysctls to `start`/`stop`. `name` may be omitted when
+/// only one instance is configured.
+pub async fn supervise_service(name: Option<&str>) -> anyhow::Result<()> {
+    if !Uid::effective().is_root() {
+        bail!("Running not from root, exiting");
+    };
+
+    let tmp_dir = MODULE_PATH.join("tmp");
+    fs::create_dir_all(&tmp_dir).await?;
+    let config = read_config().await?;
+    let mut instances = config.instances()?;
+    let instance = match name {
+        Some(name) => find_instance(instances, name)?,
+        None if instances.len() == 1 => instances.remove(0),
+        None => bail!("Several instances are configured, pick one with --instance"),
+    };
+    let name = instance.name().clone();
+    let pid_file = pid_path(&name);
+    {
+        // Registered like a started engine, so start, stop, reload and status see it.
+        let _lock = ServiceLock::acquire()?;
+        if PidFile::read(&pid_file)?.is_some() {
+            bail!("{name} is already running");
+        }
+        PidFile::current(*instance.service_type())?.write(&pid_file)?;
     }
+    let resolver = Resolver::load(*ZAPRETT_DIR_PATH);
+    let supervised = async {
+        let strategy = render_strategy(&config, &instance, &resolver, &tmp_dir, true).await?;
+        print_warnings(&instance, &strategy);
+        let apps = app_filter(&config, &PACKAGES_LIST_PATH)?;
+        let spec = EngineSpec::new(&config, &instance, strategy, &apps);
+        supervise(spec, |write| reload_engine(&name, write))
+    }
+    .await;
+    if let Err(e) = std::fs::remove_file(&pid_file) {
+        warn!("Failed to remove {}: {e}", pid_file.display());
+    }
+    supervised
+}
 
-    clear_iptables_rules().expect("clear iptables rules");
+fn find_instance(instances: Vec<Instance>, name: &str) -> anyhow::Result<Instance> {
+    instances
+        .into_iter()
+        .find(|instance| instance.name() == name)
+        .ok_or_else(|| anyhow!("No instance named {name} in the config"))
+}
+
+/// Re-reads the config and renders the strategy of instance `name` for its
+/// running supervisor. Only with `write` are the rendered files and the
+/// instance state written.
+///
+/// The supervisor is a fork of an async caller and cannot enter that runtime,
+/// so this gets a fresh thread with its own one.
+fn reload_engine(name: &str, write: bool) -> anyhow::Result<EngineSpec> {
+    let name = name.to_string();
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(async {
+                let config = read_config().await?;
+                let instance = find_instance(config.instances()?, &name)?;
+                let resolver = Resolver::load(*ZAPRETT_DIR_PATH);
+                let strategy = render_strategy(&config, &instance, &resolver, &MODULE_PATH.join("tmp"), write).await?;
+                if write {
+                    print_warnings(&instance, &strategy);
+                    EngineState::new(&config, &instance, &resolver).save(&state_path(&name))?;
+                }
+                let apps = app_filter(&config, &PACKAGES_LIST_PATH)?;
+                Ok(EngineSpec::new(&config, &instance, strategy, &apps))
+            })
+    })
+    .join()
+    .map_err(|_| anyhow!("Reload thread panicked"))?
+}
 
-    let pid_str = fs::read_to_string(MODULE_PATH.join("tmp/pid.lock")).await?;
-    let pid = pid_str.trim().parse::<i32>()?;
+pub async fn reload_service() -> anyhow::Result<()> {
+    if !Uid::effective().is_root() {
+        bail!("Running not from root, exiting");
+    };
 
-    kill(Pid::from_raw(pid), Signal::SIGKILL)?;
+    let instances = running_instances()?;
+    if instances.is_empty() {
+        bail!("zaprett is not running");
+    }
 
-    println!("zaprett service stopped");
+    for (_, pid_file) in instances {
+        kill(pid_file.pid(), Signal::SIGHUP)?;
+    }
+    println!("zaprett reload requested");
     Ok(())
 }
 
@@ -170,8 +490,9 @@ pub async fn restart_service() -> anyhow::Result<()> {
     if !Uid::effective().is_root() {
         bail!("Running not from root, exiting");
     };
-    stop_service().await?;
-    start_service().await?;
+    let _lock = ServiceLock::acquire()?;
+    stop_locked().await?;
+    start_locked().await?;
     info!("zaprett service restarted!");
     Ok(())
 }
@@ -181,19 +502,309 @@ pub async fn service_status() -> anyhow::Result<bool> {
         bail!("Running not from root, exiting");
     };
 
-    let pid_i32 = match fs::read_to_string(Path::new(*MODULE_PATH).join("tmp/pid.lock")).await {
-        Ok(s) => match s.trim().parse::<i32>() {
-            Ok(pid) => pid,
-            Err(_) => return Ok(false),
-        },
-        Err(_) => return Ok(false),
+    Ok(!running_instances()?.is_empty())
+}
+
+/// Finds the instances with a live supervisor from the pid files in `tmp`,
+/// so instances dropped from the config since start are still found.
+pub(crate) fn running_instances() -> anyhow::Result<Vec<(String, PidFile)>> {
+    let entries = match std::fs::read_dir(MODULE_PATH.join("tmp")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut instances = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "pid")
+            && let Some(name) = path.file_stem().and_then(|name| name.to_str())
+            && let Some(pid_file) = PidFile::read(&path)?
+        {
+            instances.push((name.to_string(), pid_file));
+        }
+    }
+    instances.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(instances)
+}
+
+pub async fn status_report() -> anyhow::Result<Status> {
+    if !Uid::effective().is_root() {
+        bail!("Running not from root, exiting");
     };
-    let pid = SysPid::from(pid_i32 as usize);
+
     let system = System::new_all();
-    if let Some(process) = system.process(pid) {
-        if process.name() == "zaprett" {
-            return Ok(true);
+    let instances: Vec<InstanceStatus> = running_instances()?
+        .into_iter()
+        .map(|(name, pid_file)| InstanceStatus {
+            pid: pid_file.pid as u32,
+            uptime: system
+                .process(SysPid::from(pid_file.pid as usize))
+                .map_or(0, |process| process.run_time()),
+            engine: EngineState::load(&state_path(&name)),
+            restarts: restart_count(&name),
+            errors: log_tail(&MODULE_PATH.join(format!("tmp/{name}.err"))),
+            name,
+        })
+        .collect();
+    let firewall_rules = if instances.is_empty() {
+        Vec::new()
+    } else {
+        installed_firewall(&read_config().await?).backend().list()?
+    };
+    let first = instances.first();
+    Ok(Status {
+        running: first.is_some(),
+        pid: first.map(|instance| instance.pid),
+        uptime: first.map(|instance| instance.uptime),
+        engine: first.and_then(|instance| instance.engine.clone()),
+        firewall_rules,
+        restarts: first.map_or(0, |instance| instance.restarts),
+        errors: first.map(|instance| instance.errors.clone()).unwrap_or_default(),
+        instances,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firewall::{Family, Hook, PortMatch, Protocol, RecordingFirewall, Target};
+    use std::path::PathBuf;
+
+    fn rule(family: Family, hook: Hook) -> Rule {
+        Rule {
+            family,
+            hook,
+            queues: 200..=200,
+            uid: None,
+            ports: None,
+            packets: None,
+            target: Target::Queue,
+        }
+    }
+
+    /// A temporary packages.list, removed when dropped.
+    struct PackagesList(PathBuf);
+
+    impl std::ops::Deref for PackagesList {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for PackagesList {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
         }
     }
-    Ok(false)
+
+    fn packages_list(name: &str) -> PackagesList {
+        let path = std::env::temp_dir().join(format!("zaprett-{name}-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "com.example.browser 10101 0 /data/user/0/com.example.browser default:targetSdkVersion=34 3003\n\
+             com.example.video 10102 0 /data/user/0/com.example.video default:targetSdkVersion=34 3003\n",
+        )
+        .unwrap();
+        PackagesList(path)
+    }
+
+    fn setup(config: &str, packages: &Path) -> Vec<Rule> {
+        setup_with_strategy(config, "", packages)
+    }
+
+    fn setup_with_strategy(config: &str, strategy: &str, packages: &Path) -> Vec<Rule> {
+        let config: Config = serde_json::from_str(config).unwrap();
+        let firewall = RecordingFirewall::default();
+        setup_firewall(&config, &engines(&config, strategy), packages, &firewall).unwrap();
+        firewall.installed()
+    }
+
+    fn parse(instance: &Instance, strategy: &str) -> Strategy {
+        let args = tokenize(strategy).unwrap().into_iter().map(|token| token.value).collect();
+        Strategy::parse(*instance.service_type(), args).unwrap()
+    }
+
+    fn engines(config: &Config, strategy: &str) -> Vec<(Instance, Strategy)> {
+        config
+            .instances()
+            .unwrap()
+            .into_iter()
+            .map(|instance| {
+                let strategy = parse(&instance, strategy);
+                (instance, strategy)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn default_config_queues_both_families() {
+        assert_eq!(
+            setup("{}", Path::new("/nonexistent")),
+            vec![
+                rule(Family::Ipv4, Hook::Postrouting),
+                rule(Family::Ipv4, Hook::Prerouting),
+                rule(Family::Ipv4, Hook::Forward),
+                rule(Family::Ipv6, Hook::Postrouting),
+                rule(Family::Ipv6, Hook::Prerouting),
+                rule(Family::Ipv6, Hook::Forward),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_family_config() {
+        let rules = setup(r#"{"ip_family": "ipv6"}"#, Path::new("/nonexistent"));
+        assert!(rules.iter().all(|rule| rule.family == Family::Ipv6));
+        assert_eq!(rules.len(), 3);
+    }
+
+    #[test]
+    fn whitelist_queues_only_listed_apps() {
+        let packages = packages_list("whitelist");
+        let rules = setup(
+            r#"{"ip_family": "ipv4", "app_list": "whitelist",
+                "whitelist": ["com.example.video", "com.example.missing"]}"#,
+            &packages,
+        );
+        assert_eq!(
+            rules,
+            vec![
+                Rule { uid: Some(10102), ..rule(Family::Ipv4, Hook::Postrouting) },
+                rule(Family::Ipv4, Hook::Prerouting),
+            ]
+        );
+    }
+
+    #[test]
+    fn blacklist_returns_before_queueing() {
+        let packages = packages_list("blacklist");
+        let rules = setup(
+            r#"{"ip_family": "ipv4", "app_list": "blacklist", "blacklist": ["com.example.browser"]}"#,
+            &packages,
+        );
+        assert_eq!(
+            rules,
+            vec![
+                Rule {
+                    uid: Some(10101),
+                    target: Target::Return,
+                    ..rule(Family::Ipv4, Hook::Postrouting)
+                },
+                rule(Family::Ipv4, Hook::Postrouting),
+                rule(Family::Ipv4, Hook::Prerouting),
+                rule(Family::Ipv4, Hook::Forward),
+            ]
+        );
+    }
+
+    #[test]
+    fn queue_fan_out_spans_every_worker_queue() {
+        let rules = setup(
+            r#"{"ip_family": "ipv4", "queue": {"num": 300, "count": 4}}"#,
+            Path::new("/nonexistent"),
+        );
+        assert!(rules.iter().all(|rule| rule.queues == (300..=303)));
+    }
+
+    #[test]
+    fn strategy_filters_scope_queue_rules() {
+        let rules = setup_with_strategy(
+            r#"{"ip_family": "ipv4"}"#,
+            "--filter-tcp=80,443 --dpi-desync=fake --new --filter-udp=50000-50100 --dpi-desync=fake",
+            Path::new("/nonexistent"),
+        );
+        let scoped = |hook, protocol, ports, packets| Rule {
+            ports: Some(PortMatch { protocol, ports }),
+            packets: Some(packets),
+            ..rule(Family::Ipv4, hook)
+        };
+        assert_eq!(
+            rules,
+            vec![
+                scoped(Hook::Postrouting, Protocol::Tcp, vec![80..=80, 443..=443], 1..=6),
+                scoped(Hook::Postrouting, Protocol::Udp, vec![50000..=50100], 1..=6),
+                scoped(Hook::Prerouting, Protocol::Tcp, vec![80..=80, 443..=443], 1..=3),
+                scoped(Hook::Prerouting, Protocol::Udp, vec![50000..=50100], 1..=3),
+                scoped(Hook::Forward, Protocol::Tcp, vec![80..=80, 443..=443], 1..=6),
+                scoped(Hook::Forward, Protocol::Udp, vec![50000..=50100], 1..=6),
+            ]
+        );
+    }
+
+    #[test]
+    fn unfiltered_profile_falls_back_to_catch_all() {
+        let rules = setup_with_strategy(
+            r#"{"ip_family": "ipv4"}"#,
+            "--filter-tcp=443 --dpi-desync=fake --new --dpi-desync=split2",
+            Path::new("/nonexistent"),
+        );
+        assert!(rules.iter().all(|rule| rule.ports.is_none() && rule.packets.is_none()));
+    }
+
+    #[test]
+    fn instances_get_rules_for_their_own_queues() {
+        let config: Config = serde_json::from_str(
+            r#"{"ip_family": "ipv4", "instances": [
+                {"name": "web", "queue": {"num": 200}},
+                {"name": "lua", "service_type": "nfqws2", "queue": {"num": 210, "count": 2}}
+            ]}"#,
+        )
+        .unwrap();
+        let instances = config.instances().unwrap();
+        let engines = vec![
+            (instances[0].clone(), parse(&instances[0], "--filter-tcp=443 --dpi-desync=fake")),
+            (instances[1].clone(), parse(&instances[1], "--filter-udp=443 --payload=quic_initial")),
+        ];
+        let firewall = RecordingFirewall::default();
+        setup_firewall(&config, &engines, Path::new("/nonexistent"), &firewall).unwrap();
+        let rules = firewall.installed();
+        assert!(rules.iter().all(|rule| {
+            let protocol = rule.ports.as_ref().unwrap().protocol;
+            (protocol == Protocol::Tcp && rule.queues == (200..=200))
+                || (protocol == Protocol::Udp && rule.queues == (210..=211))
+        }));
+        assert_eq!(rules.len(), 6);
+    }
+
+    #[test]
+    fn overlapping_instance_queues_are_rejected() {
+        let config: Config = serde_json::from_str(
+            r#"{"instances": [
+                {"name": "a", "queue": {"num": 200, "count": 4}},
+                {"name": "b", "queue": {"num": 203}}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(config.instances().is_err());
+    }
+
+    #[test]
+    fn clear_removes_everything_installed() {
+        let packages = packages_list("clear");
+        let config: Config =
+            serde_json::from_str(r#"{"app_list": "whitelist", "whitelist": ["com.example.video"]}"#)
+                .unwrap();
+        let firewall = RecordingFirewall::default();
+        setup_firewall(&config, &engines(&config, ""), &packages, &firewall).unwrap();
+        firewall.remove(config.ip_family().families()).unwrap();
+        assert!(firewall.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn clear_uses_the_families_rules_were_installed_for() {
+        let config: Config =
+            serde_json::from_str(r#"{"ip_family": "both", "firewall": "nftables"}"#).unwrap();
+        let firewall = RecordingFirewall::default();
+        setup_firewall(&config, &engines(&config, ""), Path::new("/nonexistent"), &firewall).unwrap();
+        let path = std::env::temp_dir().join(format!("zaprett-firewall-{}", std::process::id()));
+        FirewallState::new(&config).save(&path).unwrap();
+
+        // Stop reads the families back from the saved state, whatever the config says now.
+        let state = FirewallState::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        firewall.remove(state.ip_family.families()).unwrap();
+        assert!(firewall.list().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,93 @@
+use crate::config::{Config, Instance, QueueConfig, ServiceType};
+use crate::get_manifest;
+use crate::resolver::Resolver;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// How many lines of the engine's stderr `status --json` reports.
+const LOG_TAIL: usize = 20;
+
+/// What the running engine was started with. Written on start and reload so
+/// status reflects the running engine rather than a config edited since.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EngineState {
+    pub service_type: ServiceType,
+    pub queue: QueueConfig,
+    pub active_lists: Vec<String>,
+    pub active_ipsets: Vec<String>,
+    pub strategy: Option<StrategyInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StrategyInfo {
+    pub id: String,
+    pub version: String,
+}
+
+/// Service status. The fields after `running` repeat the first instance, as
+/// `status --json` reported before instances existed.
+#[derive(Serialize)]
+pub struct Status {
+    pub running: bool,
+    pub pid: Option<u32>,
+    pub uptime: Option<u64>,
+    #[serde(flatten)]
+    pub engine: Option<EngineState>,
+    pub firewall_rules: Vec<String>,
+    pub restarts: u32,
+    pub errors: Vec<String>,
+    pub instances: Vec<InstanceStatus>,
+}
+
+#[derive(Serialize)]
+pub struct InstanceStatus {
+    pub name: String,
+    pub pid: u32,
+    pub uptime: u64,
+    #[serde(flatten)]
+    pub engine: Option<EngineState>,
+    pub restarts: u32,
+    pub errors: Vec<String>,
+}
+
+impl EngineState {
+    pub fn new(config: &Config, instance: &Instance, resolver: &Resolver) -> Self {
+        let strategy_path = instance.strategy();
+        // The built-in default strategy has no manifest to report.
+        let strategy = Some(Path::new(strategy_path))
+            .filter(|path| !strategy_path.is_empty() && path.exists())
+            .and_then(|path| get_manifest(path, resolver).ok())
+            .map(|manifest| StrategyInfo {
+                id: manifest.id().clone(),
+                version: manifest.version().clone(),
+            });
+        Self {
+            service_type: *instance.service_type(),
+            queue: *instance.queue(),
+            active_lists: config.active_lists().clone(),
+            active_ipsets: config.active_ipsets().clone(),
+            strategy,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Option<Self> {
+        serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+}
+
+/// Returns the last [`LOG_TAIL`] lines of `path`, or nothing if it cannot be read.
+pub fn log_tail(path: &Path) -> Vec<String> {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    let lines: Vec<&str> = contents.lines().collect();
+    lines[lines.len().saturating_sub(LOG_TAIL)..]
+        .iter()
+        .map(|line| line.to_string())
+        .collect()
+}
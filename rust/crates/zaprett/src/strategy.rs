@@ -1,25 +1,88 @@
-use crate::config::Manifest;
-use regex::Regex;
-use std::collections::{HashMap, HashSet};
-use std::path::{Path, PathBuf};
-
-pub fn prepare_manifests(input: &str, regex: &Regex, manifests: &HashMap<String, Manifest>, tmp_dir: &Path) -> anyhow::Result<String> {
-    let required: HashSet<String> = regex.captures_iter(input).map(|c| c[1].to_string()).collect();
-    let mut paths: HashMap<String, PathBuf> = HashMap::new();
-    for id in &required {
-        let manifest = manifests
-            .get(id)
-            .ok_or_else(|| anyhow::anyhow!("Manifest not found: {}", id))?;
-        let path = Path::new(manifest.file());
-        let mut dst = tmp_dir.join(id);
-        if let Some(ext) = path.extension() {
-            dst.set_extension(ext);
+mod include;
+mod model;
+mod template;
+mod tokenize;
+
+pub use include::resolve_includes;
+pub use model::Strategy;
+pub use template::Variables;
+pub use tokenize::tokenize;
+
+use std::ops::RangeInclusive;
+
+/// Destination ports a strategy intercepts for one protocol.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortSet {
+    Any,
+    Ports(Vec<RangeInclusive<u16>>),
+}
+
+/// Union of the `--filter-tcp`/`--filter-udp` options of every profile.
+/// `None` for a protocol means no profile handles it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortFilters {
+    pub tcp: Option<PortSet>,
+    pub udp: Option<PortSet>,
+}
+
+impl PortSet {
+    fn parse(list: &str) -> PortSet {
+        let mut ports = Vec::new();
+        for item in list.split(',') {
+            // Inverted (`~`) and wildcard filters cannot be narrowed down to a port list.
+            let range = match item.split_once('-') {
+                Some((first, last)) => first.parse().and_then(|first| Ok(first..=last.parse()?)),
+                None => item.parse().map(|port| port..=port),
+            };
+            match range {
+                Ok(range) => ports.push(range),
+                Err(_) => return PortSet::Any,
+            }
+        }
+        PortSet::Ports(ports)
+    }
+
+    fn union(self, other: PortSet) -> PortSet {
+        match (self, other) {
+            (PortSet::Ports(mut ports), PortSet::Ports(other)) => {
+                for range in other {
+                    if !ports.contains(&range) {
+                        ports.push(range);
+                    }
+                }
+                PortSet::Ports(ports)
+            }
+            _ => PortSet::Any,
         }
-        std::fs::copy(path, &dst)?;
-        paths.insert(id.clone(), dst);
     }
-    let result = regex.replace_all(input, |caps: &regex::Captures| {
-        paths[&caps[1]].to_string_lossy().into_owned()
+}
+
+fn merge_filter(filter: &mut Option<PortSet>, list: &str) {
+    let ports = PortSet::parse(list);
+    *filter = Some(match filter.take() {
+        Some(existing) => existing.union(ports),
+        None => ports,
     });
-    Ok(result.into_owned())
-}
\ No newline at end of file
+}
+
+impl Strategy {
+    /// Collects the port filters of every profile.
+    ///
+    /// Returns `None` when any profile has no tcp/udp filter, since such a profile
+    /// may act on any traffic and only catch-all interception rules can serve it.
+    pub fn port_filters(&self) -> Option<PortFilters> {
+        let mut filters = PortFilters::default();
+        for profile in &self.profiles {
+            if profile.filter_tcp.is_none() && profile.filter_udp.is_none() {
+                return None;
+            }
+            if let Some(list) = &profile.filter_tcp {
+                merge_filter(&mut filters.tcp, list);
+            }
+            if let Some(list) = &profile.filter_udp {
+                merge_filter(&mut filters.udp, list);
+            }
+        }
+        Some(filters)
+    }
+}
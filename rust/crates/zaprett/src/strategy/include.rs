@@ -0,0 +1,109 @@
+use crate::read_file;
+use crate::resolver::Resolver;
+use crate::strategy::tokenize::{Token, tokenize};
+use anyhow::{Context, bail};
+
+/// Replaces every `${include:<id>}` argument with the arguments of strategy
+/// manifest `id`, recursively. `stack` holds the ids being expanded, starting
+/// with the including strategy's own id if it has one. Broken strategy
+/// manifests are reported by the resolver when they are looked up.
+pub fn resolve_includes(
+    tokens: Vec<Token>,
+    resolver: &Resolver,
+    stack: &mut Vec<String>,
+) -> anyhow::Result<Vec<Token>> {
+    let mut resolved = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        let Some(id) = token
+            .value
+            .strip_prefix("${include:")
+            .and_then(|rest| rest.strip_suffix('}'))
+        else {
+            resolved.push(token);
+            continue;
+        };
+        if stack.iter().any(|included| included == id) {
+            bail!("Include cycle: {} -> {id}", stack.join(" -> "));
+        }
+        let (line, column) = token.position(0);
+        let manifest = resolver
+            .lookup("manifests/strategies", id)
+            .with_context(|| format!("line {line}, column {column}: in ${{include:{id}}}"))?;
+        let content = String::from_utf8(read_file(manifest)?)
+            .with_context(|| format!("Failed to read {}", manifest.file()))?;
+        let included = tokenize(&content).with_context(|| format!("Failed to parse {id}"))?;
+
+        stack.push(id.to_string());
+        resolved.extend(resolve_includes(included, resolver, stack)?);
+        stack.pop();
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A temporary zaprett dir holding strategy manifests, removed when dropped.
+    struct Root(PathBuf);
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn strategies(name: &str, strategies: &[(&str, &str)]) -> Root {
+        let root =
+            std::env::temp_dir().join(format!("zaprett-include-{name}-{}", std::process::id()));
+        let dir = root.join("manifests/strategies");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (id, content) in strategies {
+            let file = root.join(format!("{id}.txt"));
+            std::fs::write(&file, content).unwrap();
+            let manifest = serde_json::json!({
+                "schema": 1, "id": id, "name": id, "version": "1.0.0", "author": "",
+                "description": "", "dependencies": [], "file": file,
+            });
+            std::fs::write(dir.join(format!("{id}.json")), manifest.to_string()).unwrap();
+        }
+        Root(root)
+    }
+
+    fn expand(root: &Root, strategy: &str) -> anyhow::Result<Vec<String>> {
+        let resolver = Resolver::load(&root.0);
+        let tokens = resolve_includes(tokenize(strategy)?, &resolver, &mut Vec::new())?;
+        Ok(tokens.into_iter().map(|token| token.value).collect())
+    }
+
+    #[test]
+    fn expands_nested_includes() {
+        let root = strategies(
+            "nested",
+            &[("quic", "--filter-udp=443 ${include:fake}"), ("fake", "--dpi-desync=fake")],
+        );
+        assert_eq!(
+            expand(&root, "--qnum=200 ${include:quic} --new").unwrap(),
+            ["--qnum=200", "--filter-udp=443", "--dpi-desync=fake", "--new"]
+        );
+    }
+
+    #[test]
+    fn reports_include_cycles() {
+        let root = strategies("cycle", &[("a", "--a ${include:b}"), ("b", "--b ${include:a}")]);
+        assert_eq!(
+            expand(&root, "${include:a}").unwrap_err().to_string(),
+            "Include cycle: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn reports_missing_includes_with_their_position() {
+        let root = strategies("missing", &[]);
+        assert_eq!(
+            format!("{:#}", expand(&root, "--qnum=200\n  ${include:gone}").unwrap_err()),
+            "line 2, column 3: in ${include:gone}: Manifest not found: gone"
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use crate::config::ServiceType;
+use anyhow::bail;
+
+/// A rendered strategy: the engine arguments and the profiles separated by `--new`.
+#[derive(Debug, Default)]
+pub struct Strategy {
+    pub args: Vec<String>,
+    pub profiles: Vec<Profile>,
+    /// Options missing from the tables below. They are passed on as the
+    /// engine may know them even though zaprett does not.
+    pub warnings: Vec<String>,
+}
+
+/// The options of one profile, with the ones zaprett cares about pulled out.
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub filter_tcp: Option<String>,
+    pub filter_udp: Option<String>,
+    pub filter_l7: Vec<String>,
+    pub desync: Vec<String>,
+    pub fooling: Vec<String>,
+    pub repeats: Option<u32>,
+    pub hostlists: Vec<String>,
+    pub options: Vec<(String, Option<String>)>,
+}
+
+#[derive(Clone, Copy)]
+enum Arg {
+    /// Takes no value.
+    None,
+    /// Needs a value, as `--opt=value` or `--opt value`.
+    Required(Check),
+    /// May take a value, only as `--opt=value`.
+    Optional(Check),
+}
+
+#[derive(Clone, Copy)]
+enum Check {
+    Any,
+    Number,
+    Ports,
+    List(&'static [&'static str]),
+}
+
+impl Strategy {
+    /// Parses `args` for `engine`, checking the value of every known option
+    /// against what the engine accepts. All problems are reported together.
+    pub fn parse(engine: ServiceType, args: Vec<String>) -> anyhow::Result<Self> {
+        let options = match engine {
+            ServiceType::Nfqws => NFQWS_OPTIONS,
+            ServiceType::Nfqws2 => NFQWS2_OPTIONS,
+        };
+        let mut profiles = Vec::new();
+        let mut warnings = Vec::new();
+        let mut errors = Vec::new();
+        for (index, profile_args) in args.split(|arg| arg == "--new").enumerate() {
+            let mut profile = Profile::default();
+            let prefix = |message: String| format!("profile {}: {message}", index + 1);
+            let mut args = profile_args.iter();
+            while let Some(arg) = args.next() {
+                let mut error = |message: String| errors.push(prefix(message));
+                let Some(option) = arg.strip_prefix("--") else {
+                    error(format!("unexpected argument {arg}"));
+                    continue;
+                };
+                let (name, value) = match option.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (option, None),
+                };
+                let Some(&(_, kind)) = options.iter().find(|(known, _)| *known == name) else {
+                    warnings.push(prefix(format!("unknown {} option --{name}", engine.name())));
+                    profile.options.push((name.to_string(), value));
+                    continue;
+                };
+                let value = match (kind, value) {
+                    (Arg::None, Some(_)) => {
+                        error(format!("--{name} takes no value"));
+                        continue;
+                    }
+                    (Arg::Required(_), None) => match args.next() {
+                        Some(value) => Some(value.clone()),
+                        None => {
+                            error(format!("--{name} needs a value"));
+                            continue;
+                        }
+                    },
+                    (_, value) => value,
+                };
+                if let (Arg::Required(check) | Arg::Optional(check), Some(value)) = (kind, &value)
+                    && let Err(e) = check.run(value)
+                {
+                    error(format!("--{name}: {e}"));
+                    continue;
+                }
+                profile.add(name, value);
+            }
+            profiles.push(profile);
+        }
+        if !errors.is_empty() {
+            bail!(errors.join("\n"));
+        }
+        Ok(Strategy { args, profiles, warnings })
+    }
+}
+
+impl Profile {
+    fn add(&mut self, name: &str, value: Option<String>) {
+        let list = |value: &Option<String>| -> Vec<String> {
+            value.iter().flat_map(|value| value.split(',')).map(String::from).collect()
+        };
+        match name {
+            "filter-tcp" => self.filter_tcp = value.clone(),
+            "filter-udp" => self.filter_udp = value.clone(),
+            "filter-l7" => self.filter_l7.extend(list(&value)),
+            "dpi-desync" => self.desync = list(&value),
+            "dpi-desync-fooling" => self.fooling = list(&value),
+            "dpi-desync-repeats" => self.repeats = value.as_deref().and_then(|v| v.parse().ok()),
+            "hostlist" | "hostlist-exclude" => self.hostlists.extend(value.clone()),
+            "lua-desync" => self.desync.extend(value.clone()),
+            _ => {}
+        }
+        self.options.push((name.to_string(), value));
+    }
+}
+
+impl Check {
+    fn run(self, value: &str) -> anyhow::Result<()> {
+        match self {
+            Check::Any => {}
+            Check::Number => {
+                if value.parse::<u64>().is_err() {
+                    bail!("{value} is not a number");
+                }
+            }
+            Check::Ports => {
+                for item in value.split(',') {
+                    let item = item.strip_prefix('~').unwrap_or(item);
+                    let valid = item == "*"
+                        || item
+                            .split_once('-')
+                            .map_or(item.parse::<u16>().is_ok(), |(first, last)| {
+                                first.parse::<u16>().is_ok() && last.parse::<u16>().is_ok()
+                            });
+                    if !valid {
+                        bail!("{item} is not a port or port range");
+                    }
+                }
+            }
+            Check::List(allowed) => {
+                for item in value.split(',') {
+                    if !allowed.contains(&item) {
+                        bail!("unknown value {item}, expected one of {}", allowed.join(", "));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+const DESYNC_MODES: &[&str] = &[
+    "fake", "fakeknown", "rst", "rstack", "synack", "syndata", "split", "split2", "disorder",
+    "disorder2", "multisplit", "multidisorder", "fakedsplit", "fakeddisorder", "hostfakesplit",
+    "ipfrag1", "ipfrag2", "hopbyhop", "destopt", "udplen", "tamper",
+];
+const FOOLING: &[&str] = &[
+    "none", "md5sig", "badseq", "badsum", "datanoack", "hopbyhop", "hopbyhop2", "ts",
+];
+const L3: &[&str] = &["ipv4", "ipv6"];
+const L7: &[&str] = &[
+    "http", "tls", "quic", "wireguard", "dht", "discord", "stun", "xmpp", "dns", "mtproto",
+    "unknown",
+];
+
+/// Options both engines share: process setup, profile filters and lists.
+macro_rules! common_options {
+    ($($extra:tt)*) => {
+        &[
+            ("debug", Arg::Optional(Check::Any)),
+            ("dry-run", Arg::None),
+            ("version", Arg::None),
+            ("comment", Arg::Optional(Check::Any)),
+            ("daemon", Arg::None),
+            ("pidfile", Arg::Required(Check::Any)),
+            ("user", Arg::Required(Check::Any)),
+            ("uid", Arg::Required(Check::Any)),
+            ("qnum", Arg::Required(Check::Number)),
+            ("bind-fix4", Arg::None),
+            ("bind-fix6", Arg::None),
+            ("ctrack-timeouts", Arg::Required(Check::Any)),
+            ("ctrack-disable", Arg::Optional(Check::Any)),
+            ("ipcache-lifetime", Arg::Required(Check::Number)),
+            ("ipcache-hostname", Arg::Optional(Check::Any)),
+            ("new", Arg::None),
+            ("skip", Arg::None),
+            ("filter-l3", Arg::Required(Check::List(L3))),
+            ("filter-tcp", Arg::Required(Check::Ports)),
+            ("filter-udp", Arg::Required(Check::Ports)),
+            ("filter-l7", Arg::Required(Check::List(L7))),
+            ("filter-ssid", Arg::Required(Check::Any)),
+            ("hostlist", Arg::Required(Check::Any)),
+            ("hostlist-domains", Arg::Required(Check::Any)),
+            ("hostlist-exclude", Arg::Required(Check::Any)),
+            ("hostlist-exclude-domains", Arg::Required(Check::Any)),
+            ("hostlist-auto", Arg::Required(Check::Any)),
+            ("hostlist-auto-fail-threshold", Arg::Required(Check::Number)),
+            ("hostlist-auto-fail-time", Arg::Required(Check::Number)),
+            ("hostlist-auto-retrans-threshold", Arg::Required(Check::Number)),
+            ("hostlist-auto-debug", Arg::Required(Check::Any)),
+            ("ipset", Arg::Required(Check::Any)),
+            ("ipset-ip", Arg::Required(Check::Any)),
+            ("ipset-exclude", Arg::Required(Check::Any)),
+            ("ipset-exclude-ip", Arg::Required(Check::Any)),
+            $($extra)*
+        ]
+    };
+}
+
+const NFQWS_OPTIONS: &[(&str, Arg)] = common_options![
+    ("wsize", Arg::Required(Check::Any)),
+    ("wssize", Arg::Required(Check::Any)),
+    ("wssize-cutoff", Arg::Required(Check::Any)),
+    ("hostcase", Arg::None),
+    ("hostspell", Arg::Required(Check::Any)),
+    ("hostnospace", Arg::None),
+    ("domcase", Arg::None),
+    ("methodeol", Arg::None),
+    ("synack-split", Arg::Optional(Check::List(&["syn", "synack", "acksyn"]))),
+    ("dpi-desync", Arg::Required(Check::List(DESYNC_MODES))),
+    ("dpi-desync-fwmark", Arg::Required(Check::Any)),
+    ("dpi-desync-ttl", Arg::Required(Check::Number)),
+    ("dpi-desync-ttl6", Arg::Required(Check::Number)),
+    ("dpi-desync-autottl", Arg::Optional(Check::Any)),
+    ("dpi-desync-autottl6", Arg::Optional(Check::Any)),
+    ("dpi-desync-fooling", Arg::Required(Check::List(FOOLING))),
+    ("dpi-desync-repeats", Arg::Required(Check::Number)),
+    ("dpi-desync-skip-nosni", Arg::Optional(Check::Any)),
+    ("dpi-desync-split-pos", Arg::Required(Check::Any)),
+    ("dpi-desync-split-http-req", Arg::Required(Check::Any)),
+    ("dpi-desync-split-tls", Arg::Required(Check::Any)),
+    ("dpi-desync-split-seqovl", Arg::Required(Check::Any)),
+    ("dpi-desync-split-seqovl-pattern", Arg::Required(Check::Any)),
+    ("dpi-desync-fakedsplit-pattern", Arg::Required(Check::Any)),
+    ("dpi-desync-fakedsplit-mod", Arg::Required(Check::Any)),
+    ("dpi-desync-hostfakesplit-midhost", Arg::Required(Check::Any)),
+    ("dpi-desync-hostfakesplit-mod", Arg::Required(Check::Any)),
+    ("dpi-desync-ipfrag-pos-tcp", Arg::Required(Check::Number)),
+    ("dpi-desync-ipfrag-pos-udp", Arg::Required(Check::Number)),
+    ("dpi-desync-ts-increment", Arg::Required(Check::Any)),
+    ("dpi-desync-badseq-increment", Arg::Required(Check::Any)),
+    ("dpi-desync-badack-increment", Arg::Required(Check::Any)),
+    ("dpi-desync-any-protocol", Arg::Optional(Check::Any)),
+    ("dpi-desync-fake-http", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-tls", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-tls-mod", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-unknown", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-syndata", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-quic", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-wireguard", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-dht", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-discord", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-stun", Arg::Required(Check::Any)),
+    ("dpi-desync-fake-unknown-udp", Arg::Required(Check::Any)),
+    ("dpi-desync-udplen-increment", Arg::Required(Check::Any)),
+    ("dpi-desync-udplen-pattern", Arg::Required(Check::Any)),
+    ("dpi-desync-cutoff", Arg::Required(Check::Any)),
+    ("dpi-desync-start", Arg::Required(Check::Any)),
+    ("dup", Arg::Required(Check::Number)),
+    ("dup-ttl", Arg::Required(Check::Number)),
+    ("dup-ttl6", Arg::Required(Check::Number)),
+    ("dup-autottl", Arg::Optional(Check::Any)),
+    ("dup-autottl6", Arg::Optional(Check::Any)),
+    ("dup-fooling", Arg::Required(Check::List(FOOLING))),
+    ("dup-cutoff", Arg::Required(Check::Any)),
+    ("dup-start", Arg::Required(Check::Any)),
+    ("dup-replace", Arg::Optional(Check::Any)),
+    ("dup-ts-increment", Arg::Required(Check::Any)),
+    ("dup-badseq-increment", Arg::Required(Check::Any)),
+    ("dup-badack-increment", Arg::Required(Check::Any)),
+    ("orig-ttl", Arg::Required(Check::Number)),
+    ("orig-ttl6", Arg::Required(Check::Number)),
+    ("orig-autottl", Arg::Optional(Check::Any)),
+    ("orig-autottl6", Arg::Optional(Check::Any)),
+    ("orig-mod-start", Arg::Required(Check::Any)),
+    ("orig-mod-cutoff", Arg::Required(Check::Any)),
+];
+
+const NFQWS2_OPTIONS: &[(&str, Arg)] = common_options![
+    ("fwmark", Arg::Required(Check::Any)),
+    ("name", Arg::Required(Check::Any)),
+    ("template", Arg::Optional(Check::Any)),
+    ("cookie", Arg::Optional(Check::Any)),
+    ("import", Arg::Required(Check::Any)),
+    ("lua-init", Arg::Required(Check::Any)),
+    ("lua-gc", Arg::Required(Check::Number)),
+    ("lua-desync", Arg::Required(Check::Any)),
+    ("blob", Arg::Required(Check::Any)),
+    ("payload", Arg::Required(Check::Any)),
+    ("in-range", Arg::Required(Check::Any)),
+    ("out-range", Arg::Required(Check::Any)),
+    ("filter-icmp", Arg::Required(Check::Any)),
+    ("filter-ipp", Arg::Required(Check::Any)),
+    ("writeable", Arg::Optional(Check::Any)),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tokenize;
+
+    fn parse(strategy: &str) -> anyhow::Result<Strategy> {
+        let args = tokenize(strategy).unwrap().into_iter().map(|token| token.value).collect();
+        Strategy::parse(ServiceType::Nfqws, args)
+    }
+
+    #[test]
+    fn parses_profiles() {
+        let strategy = parse(
+            "--filter-tcp=443 --hostlist '/lists/my list.txt' --dpi-desync=fake,split2 \\
+             --dpi-desync-fooling=md5sig,badsum --dpi-desync-repeats=6 --new
+             --filter-udp=50000-50100 --dpi-desync=fake",
+        )
+        .unwrap();
+        let [first, second] = &strategy.profiles[..] else {
+            panic!("expected two profiles");
+        };
+        assert_eq!(first.filter_tcp.as_deref(), Some("443"));
+        assert_eq!(first.hostlists, ["/lists/my list.txt"]);
+        assert_eq!(first.desync, ["fake", "split2"]);
+        assert_eq!(first.fooling, ["md5sig", "badsum"]);
+        assert_eq!(first.repeats, Some(6));
+        assert_eq!(second.filter_udp.as_deref(), Some("50000-50100"));
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let error = parse(
+            "--dpi-desync-fooling=md5sgi --dpi-desync-repeats=six --new
+             --filter-tcp=http --dpi-desync",
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "profile 1: --dpi-desync-fooling: unknown value md5sgi, expected one of \
+             none, md5sig, badseq, badsum, datanoack, hopbyhop, hopbyhop2, ts\n\
+             profile 1: --dpi-desync-repeats: six is not a number\n\
+             profile 2: --filter-tcp: http is not a port or port range\n\
+             profile 2: --dpi-desync needs a value"
+        );
+    }
+
+    #[test]
+    fn unknown_options_are_passed_on_with_a_warning() {
+        let strategy = parse("--filter-tcp=443 --lua-desync=fake --dpi-desync=fake").unwrap();
+        assert_eq!(strategy.warnings, ["profile 1: unknown nfqws option --lua-desync"]);
+        assert_eq!(strategy.args.len(), 3);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::config::Manifest;
+use crate::read_file;
+use crate::resolver::Resolver;
+use crate::strategy::tokenize::Token;
+use anyhow::{Context, anyhow, bail};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Registry of what `${name}` and `${namespace:id}` placeholders expand to.
+/// `$${` stands for a literal `${`.
+///
+/// Plain variables map to fixed values. Namespaces are backed by a manifest
+/// directory: `${bin:id}` copies the file of manifest `id` into the tmp dir
+/// and expands to the copy's path. Directory variables expand to a tmp dir
+/// holding the files of every manifest of a namespace. Without `write` the
+/// files are only verified.
+pub struct Variables<'a> {
+    tmp_dir: &'a Path,
+    resolver: &'a Resolver,
+    write: bool,
+    values: HashMap<&'static str, String>,
+    namespaces: HashMap<&'static str, &'static str>,
+    directories: HashMap<&'static str, &'static str>,
+}
+
+impl<'a> Variables<'a> {
+    pub fn new(tmp_dir: &'a Path, resolver: &'a Resolver, write: bool) -> Self {
+        Self {
+            tmp_dir,
+            resolver,
+            write,
+            values: HashMap::new(),
+            namespaces: HashMap::new(),
+            directories: HashMap::new(),
+        }
+    }
+
+    pub fn set(&mut self, name: &'static str, value: impl Into<String>) -> &mut Self {
+        self.values.insert(name, value.into());
+        self
+    }
+
+    /// Backs `${name:id}` with the manifests found in `dir`.
+    pub fn namespace(&mut self, name: &'static str, dir: &'static str) -> &mut Self {
+        self.namespaces.insert(name, dir);
+        self
+    }
+
+    /// Makes `${name}` the directory holding the files of every manifest of `namespace`.
+    pub fn directory(&mut self, name: &'static str, namespace: &'static str) -> &mut Self {
+        self.directories.insert(name, namespace);
+        self
+    }
+
+    fn copy(&self, manifest: &Manifest, dst: &Path) -> anyhow::Result<()> {
+        let content = read_file(manifest)?;
+        if self.write {
+            std::fs::write(dst, content)
+                .with_context(|| format!("Failed to copy {}", manifest.file()))?;
+        }
+        Ok(())
+    }
+
+    fn copy_namespace(&self, name: &str, namespace: &str) -> anyhow::Result<String> {
+        let dir = self.namespaces[namespace];
+        let manifests = self.resolver.manifests_in(dir);
+        if manifests.is_empty() {
+            bail!("Undefined variable ${{{name}}}, there are no {namespace} manifests");
+        }
+        let target = self.tmp_dir.join(name);
+        if self.write {
+            std::fs::create_dir_all(&target)?;
+        }
+        for manifest in manifests {
+            self.resolver.check(manifest)?;
+            let file_name = Path::new(manifest.file())
+                .file_name()
+                .ok_or_else(|| anyhow!("{}: file has no name", manifest.id()))?;
+            self.copy(manifest, &target.join(file_name))?;
+        }
+        Ok(target.to_string_lossy().into_owned())
+    }
+
+    fn resolve(&self, placeholder: &str) -> anyhow::Result<String> {
+        let Some((namespace, id)) = placeholder.split_once(':') else {
+            if let Some(namespace) = self.directories.get(placeholder) {
+                return self.copy_namespace(placeholder, namespace);
+            }
+            return self
+                .values
+                .get(placeholder)
+                .cloned()
+                .ok_or_else(|| anyhow!("Undefined variable ${{{placeholder}}}"));
+        };
+        let dir = self
+            .namespaces
+            .get(namespace)
+            .ok_or_else(|| anyhow!("Unknown namespace in ${{{placeholder}}}"))?;
+        let manifest = self
+            .resolver
+            .lookup(dir, id)
+            .with_context(|| format!("in ${{{placeholder}}}"))?;
+        let path = Path::new(manifest.file());
+        let mut dst = self.tmp_dir.join(id);
+        if let Some(ext) = path.extension() {
+            dst.set_extension(ext);
+        }
+        self.copy(manifest, &dst)?;
+        Ok(dst.to_string_lossy().into_owned())
+    }
+
+    /// Expands every placeholder in `tokens`, reporting all undefined ones at once.
+    pub fn expand(&self, tokens: &[Token]) -> anyhow::Result<Vec<String>> {
+        let mut args = Vec::with_capacity(tokens.len());
+        let mut errors = Vec::new();
+        for token in tokens {
+            let mut arg = String::new();
+            let mut rest = token.value.as_str();
+            while let Some(start) = rest.find('$') {
+                arg.push_str(&rest[..start]);
+                let placeholder = &rest[start..];
+                if let Some(escaped) = placeholder.strip_prefix("$${") {
+                    arg.push_str("${");
+                    rest = escaped;
+                    continue;
+                }
+                if !placeholder.starts_with("${") {
+                    arg.push('$');
+                    rest = &placeholder[1..];
+                    continue;
+                }
+                let offset = token.value.len() - placeholder.len();
+                let (line, column) = token.position(offset);
+                let Some(end) = placeholder.find('}') else {
+                    errors.push(format!("line {line}, column {column}: unterminated ${{"));
+                    rest = "";
+                    break;
+                };
+                match self.resolve(&placeholder[2..end]) {
+                    Ok(value) => arg.push_str(&value),
+                    Err(e) => errors.push(format!("line {line}, column {column}: {e:#}")),
+                }
+                rest = &placeholder[end + 1..];
+            }
+            arg.push_str(rest);
+            args.push(arg);
+        }
+        if !errors.is_empty() {
+            bail!(errors.join("\n"));
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tokenize;
+
+    fn expand(strategy: &str) -> anyhow::Result<Vec<String>> {
+        let resolver = Resolver::load(Path::new("/nonexistent"));
+        let mut variables = Variables::new(Path::new("/tmp"), &resolver, false);
+        variables.set("queue", "200");
+        variables.expand(&tokenize(strategy).unwrap())
+    }
+
+    #[test]
+    fn double_dollar_escapes_a_placeholder() {
+        assert_eq!(
+            expand("--qnum=${queue} --lua-desync='f:s=$${queue}' --cost=$5").unwrap(),
+            ["--qnum=200", "--lua-desync=f:s=${queue}", "--cost=$5"]
+        );
+    }
+
+    #[test]
+    fn reports_undefined_variables_with_their_position() {
+        assert_eq!(
+            expand("--a=${queue}\n  --b=${nope} --c=${bin:x}").unwrap_err().to_string(),
+            "line 2, column 7: Undefined variable ${nope}\n\
+             line 2, column 19: Unknown namespace in ${bin:x}"
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::bail;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Splits strategy text into arguments the way a POSIX shell would, minus
+/// expansions: `'...'` is literal, `"..."` honours `\"`, `\\`, `\$` and line
+/// continuations, an unquoted `\` escapes the next character, and `#` at the
+/// start of a word comments out the rest of the line.
+///
+/// `${...}` variables are left as they are for the strategy renderer.
+pub fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
+    let mut lexer = Lexer {
+        chars: input.chars().peekable(),
+        line: 1,
+        column: 0,
+    };
+    let mut tokens = Vec::new();
+    while let Some(token) = lexer.word()? {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// An argument along with the source line and column of each of its characters.
+pub struct Token {
+    pub value: String,
+    positions: Vec<(usize, usize)>,
+}
+
+impl Token {
+    /// Source line and column of the character at byte `offset` of the value.
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let index = self.value[..offset].chars().count();
+        self.positions[index.min(self.positions.len() - 1)]
+    }
+
+    fn push(&mut self, c: char, position: (usize, usize)) {
+        self.value.push(c);
+        self.positions.push(position);
+    }
+}
+
+fn starts_with_line_break(mut chars: Peekable<Chars>) -> bool {
+    match chars.next() {
+        Some('\n') => true,
+        Some('\r') => chars.next() == Some('\n'),
+        _ => false,
+    }
+}
+
+struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer<'_> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 0;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    /// Skips blanks, comments and line continuations between words.
+    fn skip_separators(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                c if c.is_whitespace() => {
+                    self.next();
+                }
+                '#' => {
+                    while self.chars.peek().is_some_and(|&c| c != '\n') {
+                        self.next();
+                    }
+                }
+                '\\' => {
+                    let mut ahead = self.chars.clone();
+                    ahead.next();
+                    if !starts_with_line_break(ahead) {
+                        return;
+                    }
+                    self.next();
+                    self.line_break();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Consumes the `\n` or `\r\n` following a backslash, if there is one.
+    fn line_break(&mut self) -> bool {
+        if !starts_with_line_break(self.chars.clone()) {
+            return false;
+        }
+        if self.next() == Some('\r') {
+            self.next();
+        }
+        true
+    }
+
+    fn position(&self) -> (usize, usize) {
+        (self.line, self.column)
+    }
+
+    fn word(&mut self) -> anyhow::Result<Option<Token>> {
+        self.skip_separators();
+        if self.chars.peek().is_none() {
+            return Ok(None);
+        }
+
+        let mut word = Token {
+            value: String::new(),
+            positions: Vec::new(),
+        };
+        while let Some(&c) = self.chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            let (line, column) = (self.line, self.column + 1);
+            self.next();
+            match c {
+                '\'' => loop {
+                    match self.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c, self.position()),
+                        None => bail!("Unterminated single quote at line {line}, column {column}"),
+                    }
+                },
+                '"' => loop {
+                    match self.next() {
+                        Some('"') => break,
+                        Some('\\') if self.line_break() => {}
+                        Some('\\') => match self.next() {
+                            Some(c @ ('"' | '\\' | '$')) => word.push(c, self.position()),
+                            Some(c) => {
+                                let escape = (self.line, self.column - 1);
+                                word.push('\\', escape);
+                                word.push(c, self.position());
+                            }
+                            None => bail!("Unterminated double quote at line {line}, column {column}"),
+                        },
+                        Some(c) => word.push(c, self.position()),
+                        None => bail!("Unterminated double quote at line {line}, column {column}"),
+                    }
+                },
+                '\\' if self.line_break() => {}
+                '\\' => match self.next() {
+                    Some(c) => word.push(c, self.position()),
+                    None => bail!("Dangling backslash at line {line}, column {column}"),
+                },
+                c => word.push(c, (line, column)),
+            }
+        }
+        Ok(Some(word))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(input: &str) -> Vec<String> {
+        tokenize(input).unwrap().into_iter().map(|token| token.value).collect()
+    }
+
+    #[test]
+    fn splits_quoted_and_escaped_words() {
+        let args = args(
+            r#"--hostlist="/storage/emulated/0/my lists/a.txt" --lua-desync='fake:blob=x y'
+               --name=a\ b --empty="" "say \"hi\"""#,
+        );
+        assert_eq!(
+            args,
+            [
+                "--hostlist=/storage/emulated/0/my lists/a.txt",
+                "--lua-desync=fake:blob=x y",
+                "--name=a b",
+                "--empty=",
+                "say \"hi\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_joins_continuations() {
+        let args = args(
+            "# google\n--filter-tcp=443 \\\n  --dpi-desync=fake # trailing\n--new#not-a-comment",
+        );
+        assert_eq!(args, ["--filter-tcp=443", "--dpi-desync=fake", "--new#not-a-comment"]);
+    }
+
+    #[test]
+    fn joins_crlf_continuations() {
+        let args = args("--filter-tcp=443 \\\r\n  --dpi-desync=fake\r\n--new \"a\\\r\nb\" c\\\r\nd");
+        assert_eq!(args, ["--filter-tcp=443", "--dpi-desync=fake", "--new", "ab", "cd"]);
+    }
+
+    #[test]
+    fn reports_where_a_quote_is_left_open() {
+        let Err(error) = tokenize("--a=1\n  --b='oops") else {
+            panic!("expected an error");
+        };
+        assert_eq!(error.to_string(), "Unterminated single quote at line 2, column 7");
+    }
+}
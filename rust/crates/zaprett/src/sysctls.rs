@@ -0,0 +1,71 @@
+use anyhow::Context;
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use sysctl::{Ctl, CtlValue, Sysctl};
+
+/// Kernel settings the service changes while running, with the value it needs.
+pub const MANAGED_SYSCTLS: &[(&str, &str)] = &[("net.netfilter.nf_conntrack_tcp_be_liberal", "1")];
+
+/// Writes the current value of every managed sysctl to `snapshot`.
+pub fn save_sysctls(snapshot: &Path) -> anyhow::Result<()> {
+    let values = MANAGED_SYSCTLS
+        .iter()
+        .map(|(name, _)| {
+            let value = Ctl::new(name)?.value_string()?;
+            Ok((name.to_string(), value))
+        })
+        .collect::<anyhow::Result<BTreeMap<String, String>>>()?;
+    fs::write(snapshot, serde_json::to_string_pretty(&values)?)
+        .with_context(|| format!("Failed to write {}", snapshot.display()))
+}
+
+pub fn set_sysctls() -> anyhow::Result<()> {
+    MANAGED_SYSCTLS.iter().try_for_each(|(name, value)| {
+        Ctl::new(name)?
+            .set_value(CtlValue::String(value.to_string()))
+            .with_context(|| format!("Failed to set {name}"))?;
+        Ok(())
+    })
+}
+
+/// Puts back the values saved by [`save_sysctls`] and drops the snapshot.
+/// A missing snapshot means there is nothing to restore, a corrupt one is
+/// discarded as its values cannot be trusted.
+pub fn restore_sysctls(snapshot: &Path) -> anyhow::Result<()> {
+    let content = match fs::read_to_string(snapshot) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let values: BTreeMap<String, String> = match serde_json::from_str(&content) {
+        Ok(values) => values,
+        Err(e) => {
+            warn!("Discarding corrupt sysctl snapshot {}: {e}", snapshot.display());
+            fs::remove_file(snapshot)?;
+            return Ok(());
+        }
+    };
+    for (name, value) in values {
+        Ctl::new(&name)?
+            .set_value(CtlValue::String(value))
+            .with_context(|| format!("Failed to restore {name}"))?;
+    }
+    fs::remove_file(snapshot)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_snapshot_is_discarded() {
+        let snapshot = std::env::temp_dir().join(format!("zaprett-sysctl-{}", std::process::id()));
+        fs::write(&snapshot, "{\"net.netfilter.nf_conntrack_tcp_be_lib").unwrap();
+        restore_sysctls(&snapshot).unwrap();
+        assert!(!snapshot.exists());
+    }
+}